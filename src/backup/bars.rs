@@ -1,9 +1,8 @@
-use std::{thread::{JoinHandle, self}, time::Duration, io::{Read, self, Write}, sync::{OnceLock, RwLock}, path::PathBuf};
+use std::{thread::{JoinHandle, self}, time::Duration, io::{self, Write}, sync::{atomic::{AtomicU64, Ordering}, OnceLock, RwLock}, path::PathBuf};
 use fs_id::FileID;
 use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
-use crate::{config::{assert_config, config}, static_ptr::StaticPointer};
-use super::{metadata, tar::scan_path};
-use xz2::read::XzEncoder;
+use crate::config::{assert_config, config};
+use super::{data_len, metadata, tar::scan_path};
 use colored::Colorize;
 
 #[derive(Debug)]
@@ -12,7 +11,7 @@ pub struct BarsHandler {
 	pub xz_bar: ProgressBar,
 	pub status_bar: ProgressBar,
 	pub multi: MultiProgress,
-	ticker: Option<JoinHandle<u8>>,
+	ticker: Option<JoinHandle<()>>,
 	loader: JoinHandle<()>,
 }
 
@@ -22,6 +21,10 @@ pub const PROGRESS_BAR: &str = "█░";
 
 static BARS_HANDLER: RwLock<OnceLock<BarsHandler>> = RwLock::new(OnceLock::new());
 
+//the bytes read and written by every compressor, shown by the ticker
+static COMPRESSED_IN: AtomicU64 = AtomicU64::new(0);
+static COMPRESSED_OUT: AtomicU64 = AtomicU64::new(0);
+
 pub fn spinner_chars() -> &'static str {
 	if supports_unicode::supports_unicode() {
 		UNICODE_SPINNER
@@ -92,34 +95,66 @@ impl BarsHandler {
 		multi.add(status_bar.clone());
 		status_bar.set_message("Starting...");
 		multi.set_move_cursor(true);
+		COMPRESSED_IN.store(0, Ordering::Relaxed);
+		COMPRESSED_OUT.store(0, Ordering::Relaxed);
+		let ticker = thread::spawn({
+			let xz_bar = xz_bar.clone();
+			let status_bar = status_bar.clone();
+			move || {
+				let interval_duration = Duration::from_millis(166);
+				let mut prev_out = 0;
+				let mut counter = 0u8;
+				loop {
+					xz_bar.set_position(COMPRESSED_IN.load(Ordering::Relaxed));
+					let compressed_out = COMPRESSED_OUT.load(Ordering::Relaxed);
+					if prev_out < compressed_out {
+						status_bar.set_message(format!(
+							"Writing {} compressed bytes",
+							(compressed_out - prev_out).to_string().cyan().bold()
+						));
+						prev_out = compressed_out;
+					}
+					counter = counter.wrapping_add(1);
+					if counter & 16 == 0 {
+						xz_bar.suspend(|| {
+							BarsHandler::redo_terminal();
+						});
+					}
+					thread::sleep(interval_duration);
+					if xz_bar.is_finished() {
+						break;
+					}
+				}
+			}
+		});
 		let bars_handler = Self {
 			tar_bar: tar_bar.clone(),
 			xz_bar: xz_bar.clone(),
 			status_bar: status_bar.clone(),
 			multi,
-			ticker: None,
+			ticker: Some(ticker),
 			loader: thread::spawn(move || {
 				let config = config!();
-				for path_ref in &config.paths {
-					if let Ok(path) = path_ref.canonicalize() {
-						scan_path(
-							output_file_id,
-							path,
-							PathBuf::new(),
-							|_, _| true,
-							&mut |path, _| {
-								if !xz_bar.is_finished() {
-									if let Ok(meta) = metadata(path) {
-										xz_bar.inc_length(meta.len());
-									}
-								}
-								if !tar_bar.is_finished() {
-									tar_bar.inc_length(1);
+				for path in &config.paths {
+					config.enter_root(path);
+					scan_path(
+						&[output_file_id],
+						path.clone(),
+						PathBuf::new(),
+						&mut Vec::new(),
+						|_, _| true,
+						&mut |path, _, _| {
+							if !xz_bar.is_finished() {
+								if let Ok(meta) = metadata(path) {
+									xz_bar.inc_length(data_len(&meta));
 								}
-								Ok(())
 							}
-						);
-					}
+							if !tar_bar.is_finished() {
+								tar_bar.inc_length(1);
+							}
+							Ok(())
+						}
+					);
 				}
 				if !xz_bar.is_finished() {
 					xz_bar.inc_length(xz_bar.length().unwrap() / 30);
@@ -134,49 +169,14 @@ impl BarsHandler {
 		Ok(())
 	}
 
-	/// SAFETY: the caller must make sure `BARS_HANDLER` containts a value by checking `Config.progress_bars`
-	pub unsafe fn set_ticker<R: Read + 'static>(compressor_ptr: *const XzEncoder<R>) {
-		static mut FINISH: bool = false;
-		let mut bars_handler = BARS_HANDLER.write().unwrap();
-		debug_assert!(bars_handler.get_mut().is_some());
-		let bars_handler = bars_handler.get_mut().unwrap_unchecked();
-		let mut counter = if let Some(old_ticker) = bars_handler.ticker.take() {
-			FINISH = true;
-			let counter = old_ticker.join().unwrap();
-			FINISH = false;
-			counter
-		} else {
-			0
-		};
-		let xz_bar = bars_handler.xz_bar.clone();
-		let status_bar = bars_handler.status_bar.clone();
-		let compressor_ptr = StaticPointer::new(compressor_ptr);
-		bars_handler.ticker = Some(thread::spawn(move || {
-			let interval_duration = Duration::from_millis(166);
-			let mut prev_out = 0;
-			// SAFETY: The compressor is dropped only after the ticker thread ends.
-			let compressor = unsafe { compressor_ptr.deref() };
-			loop {
-				xz_bar.set_position(compressor.total_in());
-				if prev_out < compressor.total_out() {
-					status_bar.set_message(format!(
-						"Writing {} compressed bytes",
-						(compressor.total_out() - prev_out).to_string().cyan().bold()
-					));
-					prev_out = compressor.total_out();
-				}
-				counter = counter.wrapping_add(1);
-				if counter & 16 == 0 {
-					xz_bar.suspend(|| {
-						BarsHandler::redo_terminal();
-					});
-				}
-				thread::sleep(interval_duration);
-				if FINISH || xz_bar.is_finished() {
-					break counter;
-				}
-			}
-		}));
+	/// Counts `read` bytes as read by a compressor.
+	pub fn add_compressed_in(read: u64) {
+		COMPRESSED_IN.fetch_add(read, Ordering::Relaxed);
+	}
+
+	/// Counts `written` bytes as written by a compressor.
+	pub fn add_compressed_out(written: u64) {
+		COMPRESSED_OUT.fetch_add(written, Ordering::Relaxed);
 	}
 
 	/// SAFETY: the caller must make sure `BARS_HANDLER` containts a value by checking `Config.progress_bars`
@@ -0,0 +1,195 @@
+use std::{
+	fs::File,
+	io::{self, Read},
+	path::PathBuf,
+	thread,
+	time::Instant,
+};
+use colored::Colorize;
+use indicatif::HumanBytes;
+use liblzma::read::XzEncoder;
+use toml::Value;
+use crate::{config::{self, assert_config, config}, error::ResultExt};
+use super::{check_xz_config, dict_size, estimate, memory_limit, stream_builder};
+
+//a configuration slower than the fastest by less than this is preferred if it uses less memory
+const TOLERANCE: f64 = 0.95;
+
+struct Run {
+	threads: u32,
+	block_size: u64,
+	memory_usage: u64,
+	speed: f64,
+}
+
+//how much of a file is read at a time
+const CHUNK_SIZE: usize = 1 << 20;
+
+//reads the files in order until `size` bytes are read, returning them and how fast they were read,
+//which can be the speed of the page cache rather than the one of the disks if they were read recently
+fn read_files(files: &[(PathBuf, u64)], size: u64) -> (Vec<u8>, f64) {
+	let mut data = Vec::new();
+	let mut chunk = vec![0; CHUNK_SIZE];
+	let start = Instant::now();
+	'files: for (path, _) in files {
+		let Ok(mut file) = File::open(path) else {
+			continue;
+		};
+		loop {
+			let remaining = size - data.len() as u64;
+			if remaining == 0 {
+				break 'files;
+			}
+			let len = remaining.min(CHUNK_SIZE as u64) as usize;
+			match file.read(&mut chunk[..len]) {
+				Ok(0) => break,
+				Ok(read) => data.extend_from_slice(&chunk[..read]),
+				Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+				Err(_) => break,
+			}
+		}
+	}
+	let speed = data.len() as f64 / start.elapsed().as_secs_f64();
+	(data, speed)
+}
+
+fn speed_to_string(speed: f64) -> String {
+	format!("{}/s", HumanBytes(speed as u64))
+}
+
+pub fn run(size: u64) -> io::Result<()> {
+	let config = config!();
+	assert_config!(size == 0, "`{}` cannot be 0", "--size".yellow().bold());
+	let Some(size) = size.checked_mul(1 << 20) else {
+		return Err(io::Error::other(format!("`{}` is too big", "--size".yellow().bold())));
+	};
+	check_xz_config()?;
+	println!("{} the files to backup...", "Scanning".cyan().bold());
+	let scan = estimate::scan();
+	let (data, read_speed) = read_files(&scan.files, size);
+	if data.is_empty() {
+		println!("There is nothing to compress");
+		return Ok(());
+	}
+	println!(
+		"{} {} of data at {} (the files may have been read from the cache)",
+		"Read".cyan().bold(),
+		HumanBytes(data.len() as u64).to_string().cyan().bold(),
+		speed_to_string(read_speed).cyan().bold()
+	);
+	let available_threads = thread::available_parallelism()?.get() as u32;
+	let thread_counts: Vec<u32> = (0..u32::BITS)
+		.map(|shift| 1 << shift)
+		.take_while(|threads| *threads < available_threads)
+		.chain([available_threads])
+		.collect();
+	let dict_size = dict_size(config.level) as u64;
+	let block_sizes: Vec<u64> = [1, 2, 3, 6].map(|factor| (dict_size * factor).max(1 << 20)).to_vec();
+	let memory_limit = memory_limit();
+	println!(
+		"\n{:>7}  {:>10}  {:>12}  {:>12}",
+		"Threads".bold(),
+		"Block size".bold(),
+		"Memory".bold(),
+		"Speed".bold()
+	);
+	let mut runs = Vec::new();
+	let mut skipped_threads = Vec::new();
+	for &threads in &thread_counts {
+		for &block_size in &block_sizes {
+			//more threads than blocks can't be measured, they would just sit idle
+			if threads > 1 && (threads as u64) > (data.len() as u64).div_ceil(block_size) {
+				if skipped_threads.last() != Some(&threads) {
+					skipped_threads.push(threads);
+				}
+				continue;
+			}
+			let builder = {
+				let mut builder = stream_builder(config.level, &config.filters, threads)?;
+				builder.block_size(block_size);
+				builder
+			};
+			let memory_usage = builder.memusage();
+			if memory_limit.is_some_and(|(memory_limit, _)| memory_usage > memory_limit) {
+				continue;
+			}
+			let mut encoder = XzEncoder::new_stream(data.as_slice(), builder.encoder().to_io_result()?);
+			let start = Instant::now();
+			io::copy(&mut encoder, &mut io::sink())?;
+			let speed = data.len() as f64 / start.elapsed().as_secs_f64();
+			println!(
+				"{:>7}  {:>10}  {:>12}  {:>12}",
+				threads,
+				HumanBytes(block_size).to_string(),
+				HumanBytes(memory_usage).to_string(),
+				speed_to_string(speed)
+			);
+			runs.push(Run { threads, block_size, memory_usage, speed });
+		}
+	}
+	if !skipped_threads.is_empty() {
+		println!(
+			"{} {} threads were not measured with every block size, {} of data doesn't fill a block per thread \
+			 (a bigger `{}` measures them)",
+			"notice:".cyan().bold(),
+			skipped_threads.iter().map(u32::to_string).collect::<Vec<_>>().join(", "),
+			HumanBytes(data.len() as u64),
+			"--size".cyan().bold()
+		);
+	}
+	let Some(fastest) = runs.iter().map(|run| run.speed).reduce(f64::max) else {
+		let (memory_limit, name) = memory_limit.unwrap();
+		return Err(io::Error::other(format!(
+			"no configuration fits within the {} allowed by {}",
+			HumanBytes(memory_limit),
+			name.yellow().bold()
+		)));
+	};
+	//compressing faster than the files can be read is pointless, so less memory is used instead
+	let target = (fastest * TOLERANCE).min(read_speed);
+	let best = runs
+		.iter()
+		.filter(|run| run.speed >= target)
+		.min_by_key(|run| (run.memory_usage, run.threads))
+		.unwrap();
+	if fastest < read_speed {
+		println!(
+			"\nCompressing is slower than reading the files ({} against {})",
+			speed_to_string(fastest).cyan().bold(),
+			speed_to_string(read_speed).cyan().bold()
+		);
+	} else {
+		println!(
+			"\nReading the files is slower than compressing them ({} against {})",
+			speed_to_string(read_speed).cyan().bold(),
+			speed_to_string(fastest).cyan().bold()
+		);
+	}
+	//liblzma defaults to 3 times the dictionary size, which is also what the reproducible mode uses
+	let block_size = if best.block_size == (dict_size * 3).max(1 << 20) {
+		0
+	} else {
+		best.block_size
+	};
+	let msg = format!(
+		"{} use {} XZ threads and blocks of {} ({} of memory, {})\n\
+		 `{}` would be set to {} and `{}` to {}",
+		"Suggestion:".green().bold(),
+		best.threads.to_string().cyan().bold(),
+		HumanBytes(best.block_size).to_string().cyan().bold(),
+		HumanBytes(best.memory_usage),
+		speed_to_string(best.speed),
+		"xz.threads".cyan().bold(),
+		best.threads,
+		"xz.block_size".cyan().bold(),
+		block_size
+	);
+	if best.threads == config.threads && block_size == config.block_size {
+		println!("{msg}\nThe configuration already uses these settings");
+		return Ok(());
+	}
+	config::update_file(msg, |table| {
+		table["xz"]["threads"] = Value::Integer(best.threads as i64);
+		table["xz"]["block_size"] = Value::Integer(block_size as i64);
+	})
+}
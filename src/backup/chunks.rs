@@ -0,0 +1,64 @@
+use std::{
+	io::{self, Read, Write},
+	sync::mpsc::{self, Receiver, SyncSender},
+};
+
+//how many chunks can be waiting to be read before the writer blocks
+const MAX_PENDING_CHUNKS: usize = 16;
+
+/// Creates a bounded channel of bytes, the reader returns EOF once the writer is dropped.
+pub fn channel() -> (ChunkWriter, ChunkReader) {
+	let (sender, receiver) = mpsc::sync_channel(MAX_PENDING_CHUNKS);
+	(ChunkWriter(sender), ChunkReader {
+		receiver,
+		chunk: Vec::new(),
+		position: 0,
+		received: 0,
+	})
+}
+
+pub struct ChunkWriter(SyncSender<Vec<u8>>);
+
+impl Write for ChunkWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		self.0
+			.send(buf.to_vec())
+			.map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the reader was dropped"))?;
+		Ok(buf.len())
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		Ok(())
+	}
+}
+
+pub struct ChunkReader {
+	receiver: Receiver<Vec<u8>>,
+	chunk: Vec<u8>,
+	position: usize,
+	received: u64,
+}
+
+impl ChunkReader {
+	/// Amount of bytes read so far.
+	pub fn received(&self) -> u64 {
+		self.received
+	}
+}
+
+impl Read for ChunkReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		while self.position == self.chunk.len() {
+			let Ok(chunk) = self.receiver.recv() else {
+				return Ok(0);
+			};
+			self.chunk = chunk;
+			self.position = 0;
+		}
+		let read = (self.chunk.len() - self.position).min(buf.len());
+		buf[..read].copy_from_slice(&self.chunk[self.position..self.position + read]);
+		self.position += read;
+		self.received += read as u64;
+		Ok(read)
+	}
+}
@@ -0,0 +1,175 @@
+use std::{
+	collections::HashSet,
+	fs::File,
+	io::{self, Read, Seek, SeekFrom},
+	path::PathBuf,
+	time::{Duration, Instant},
+};
+use colored::Colorize;
+use indicatif::{HumanBytes, HumanDuration};
+use liblzma::read::XzEncoder;
+use crate::{config::{assert_config, config}, error::ResultExt};
+use super::{block_size, check_xz_config, choose_threads, data_len, dict_size, metadata, store, stream_builder, tar::scan_path};
+
+//the size of the pieces of data sampled from the files
+const CHUNK_SIZE: u64 = 64 * 1024;
+
+//the sample is kept in memory to compress it multiple times
+const MAX_SAMPLE_SIZE: u64 = 256 << 20;
+
+#[derive(Default)]
+pub(super) struct Scan {
+	pub files: Vec<(PathBuf, u64)>,
+	entries: u64,
+	tar_size: u64,
+	pub data_size: u64,
+	stored_size: u64,
+}
+
+//counts what the archiver would archive, the holes of sparse files and the files already archived
+//through another hard link are left out like it does
+pub(super) fn scan() -> Scan {
+	let config = config!();
+	let mut scan = Scan::default();
+	let mut archived_files = HashSet::new();
+	for path in &config.paths {
+		config.enter_root(path);
+		scan_path(&[], path.clone(), PathBuf::new(), &mut Vec::new(), |_, _| true, &mut |path, _, file_id| {
+			let meta = metadata(path)?;
+			scan.entries += 1;
+			scan.tar_size += 512;
+			if meta.is_file() && file_id.map_or(true, |file_id| archived_files.insert(file_id)) {
+				let len = data_len(&meta);
+				scan.tar_size += len.next_multiple_of(512);
+				if store::is_stored(path) {
+					scan.stored_size += len;
+				} else {
+					scan.data_size += len;
+					scan.files.push((path.clone(), len));
+				}
+			}
+			Ok(())
+		});
+	}
+	//the end of the archive
+	scan.tar_size += 1024;
+	scan
+}
+
+//takes chunks evenly spread across all of the files until the wanted fraction of the data is sampled
+fn sample(files: &[(PathBuf, u64)], fraction: f64) -> Vec<u8> {
+	let mut sample = Vec::new();
+	let mut budget = 0.0;
+	for (path, len) in files {
+		let mut file = None;
+		let mut offset = 0;
+		while offset < *len {
+			let chunk_len = CHUNK_SIZE.min(len - offset);
+			budget += chunk_len as f64 * fraction;
+			if budget >= chunk_len as f64 {
+				budget -= chunk_len as f64;
+				if file.is_none() {
+					file = File::open(path).ok();
+				}
+				if let Some(file) = &mut file {
+					if file.seek(SeekFrom::Start(offset)).is_ok() {
+						let _ = file.take(chunk_len).read_to_end(&mut sample);
+					}
+				}
+			}
+			offset += chunk_len;
+		}
+	}
+	sample
+}
+
+pub fn run(fraction: f64, levels: &[u32]) -> io::Result<()> {
+	let config = config!();
+	assert_config!(
+		!(fraction > 0.0 && fraction <= 1.0),
+		"`{}` must be between 0 and 1",
+		"--sample".yellow().bold()
+	);
+	let mut levels = if levels.is_empty() {
+		vec![0, 3, 6, 9, config.level]
+	} else {
+		levels.to_vec()
+	};
+	levels.sort();
+	levels.dedup();
+	assert_config!(
+		levels.last().is_some_and(|level| *level > 9),
+		"`{}` cannot exceed 9",
+		"--levels".yellow().bold()
+	);
+	check_xz_config()?;
+	let threads = choose_threads()?;
+	println!("{} the files to backup...", "Scanning".cyan().bold());
+	let scan = scan();
+	let fraction = fraction.min(MAX_SAMPLE_SIZE as f64 / scan.data_size.max(1) as f64);
+	println!(
+		"Found {} entries, {} of data to compress and {} of data to store",
+		scan.entries.to_string().cyan().bold(),
+		HumanBytes(scan.data_size).to_string().cyan().bold(),
+		HumanBytes(scan.stored_size).to_string().cyan().bold()
+	);
+	let sample = sample(&scan.files, fraction);
+	println!(
+		"{} {} of data ({:.2}%)",
+		"Sampled".cyan().bold(),
+		HumanBytes(sample.len() as u64).to_string().cyan().bold(),
+		fraction * 100.0
+	);
+	if sample.is_empty() {
+		println!("There is nothing to compress, the backup will be around {}", HumanBytes(scan.tar_size));
+		return Ok(());
+	}
+	let compressed_size = scan.tar_size - scan.stored_size;
+	println!(
+		"\n{:>5}  {:>12}  {:>7}  {:>16}  {:>12}",
+		"Level".bold(),
+		"Size".bold(),
+		"Ratio".bold(),
+		"Time".bold(),
+		"Memory".bold()
+	);
+	for level in levels {
+		//the sample is compressed by a single thread, since it would rarely fill more than one block
+		let mut encoder = XzEncoder::new_stream(
+			sample.as_slice(),
+			stream_builder(level, &config.filters, 1)?.encoder().to_io_result()?
+		);
+		let start = Instant::now();
+		let sample_out = io::copy(&mut encoder, &mut io::sink())?;
+		let elapsed = start.elapsed();
+		let ratio = sample_out as f64 / sample.len() as f64;
+		let projected_size = (compressed_size as f64 * ratio) as u64 + scan.stored_size;
+		let block_size = match block_size(dict_size(level)) {
+			0 => (dict_size(level) as u64 * 3).max(1 << 20),
+			block_size => block_size,
+		};
+		let used_threads = (threads as u64).min(compressed_size.div_ceil(block_size)).max(1);
+		let projected_time = Duration::from_secs_f64(
+			elapsed.as_secs_f64() * compressed_size as f64 / sample.len() as f64 / used_threads as f64
+		);
+		let memory_usage = stream_builder(level, &config.filters, threads)?.memusage();
+		let row = format!(
+			"{:>5}  {:>12}  {:>7}  {:>16}  {:>12}",
+			level,
+			HumanBytes(projected_size).to_string(),
+			format!("{:.1}%", projected_size as f64 / scan.tar_size as f64 * 100.0),
+			HumanDuration(projected_time).to_string(),
+			HumanBytes(memory_usage).to_string()
+		);
+		if level == config.level {
+			println!("{}", row.green().bold());
+		} else {
+			println!("{row}");
+		}
+	}
+	println!(
+		"\nThe configured level is highlighted, times assume {} XZ threads and don't include reading the files",
+		threads.to_string().cyan().bold()
+	);
+	Ok(())
+}
@@ -1,95 +1,364 @@
 use fs_id::GetID;
-use xz2::{read::XzEncoder, stream::MtStreamBuilder};
-use crate::{backup::tar::SUBARCHIVE_VALUES, config::{assert_config, config}, error::ResultExt, input};
-use self::bars::BarsHandler;
-use std::{fs::{self, File, Metadata}, io::{self, Read}, path::Path, process, sync::OnceLock, thread};
-use os_pipe::PipeReader;
+use liblzma::{
+	read::XzEncoder,
+	stream::{self, Check, Filters, LzmaOptions, MtStreamBuilder, PRESET_EXTREME},
+};
+use crate::{config::{assert_config, config, Filter, IntegrityCheck, MatchFinder, MemoryLimit, TarFormat}, error::ResultExt, input};
+use self::{bars::BarsHandler, store::{SegmentReader, StoreMode, StoreWriter}};
+use std::{fs::{self, File, Metadata}, io::{self, Read, Write}, path::Path, process, sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc}, thread};
 use colored::Colorize;
+use indicatif::HumanBytes;
+use sysinfo::{MemoryRefreshKind, System};
 
 pub mod bars;
+pub mod bench;
+mod chunks;
+pub mod estimate;
+mod pax;
+mod sparse;
+mod store;
 mod tar;
 
+//counts the bytes read by a compressor for the progress bars
 struct ReaderObserver<R: Read>(R);
 
 impl<R: Read> Read for ReaderObserver<R> {
 	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
 		let read = self.0.read(buf)?;
-		if read > 0 {
+		BarsHandler::add_compressed_in(read as u64);
+		Ok(read)
+	}
+}
+
+//counts the bytes written by a compressor for the progress bars, or prints them without them
+struct WriterObserver<W: Write>(W);
+
+impl<W: Write> Write for WriterObserver<W> {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.0.write(buf)?;
+		if *config!(progress_bars) {
+			BarsHandler::add_compressed_out(written as u64);
+		} else if written > 0 {
 			println!(
 				"Writing {} compressed bytes",
-				read.to_string().cyan().bold()
+				written.to_string().cyan().bold()
 			);
 		}
-		Ok(read)
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.0.flush()
 	}
 }
 
-pub trait BorrowCompressor : Read {
-	fn borrow_compressor(&mut self) -> &mut XzEncoder<PipeReader>;
+pub fn metadata(path: impl AsRef<Path>) -> io::Result<Metadata> {
+	if config!().follow_symlinks() {
+		path.as_ref().metadata()
+	} else {
+		path.as_ref().symlink_metadata()
+	}
 }
 
-impl BorrowCompressor for XzEncoder<PipeReader> {
-	fn borrow_compressor(&mut self) -> &mut XzEncoder<PipeReader> {
-		self
+//the amount of data of a file that will be archived, which leaves out the holes of sparse files
+#[cfg(unix)]
+fn data_len(meta: &Metadata) -> u64 {
+	use std::os::unix::fs::MetadataExt;
+	if *config!(sparse) {
+		meta.len().min(meta.blocks() * 512)
+	} else {
+		meta.len()
 	}
 }
 
-impl BorrowCompressor for ReaderObserver<XzEncoder<PipeReader>> {
-	fn borrow_compressor(&mut self) -> &mut XzEncoder<PipeReader> {
-		&mut self.0
+#[cfg(windows)]
+fn data_len(meta: &Metadata) -> u64 {
+	meta.len()
+}
+
+//the dictionary size of each XZ preset
+const DICT_SIZES: [u32; 10] = [
+	1 << 18, 1 << 20, 1 << 21, 1 << 22, 1 << 22,
+	1 << 23, 1 << 23, 1 << 24, 1 << 25, 1 << 26,
+];
+
+fn dict_size(level: u32) -> u32 {
+	match config!().dict_size {
+		0 => DICT_SIZES[level as usize],
+		dict_size => dict_size,
 	}
 }
 
-pub fn metadata(path: impl AsRef<Path>) -> io::Result<Metadata> {
-	if *config!(follow_symlinks) {
-		path.as_ref().metadata()
+fn block_size(dict_size: u32) -> u64 {
+	//by default XZ uses 3 times the dictionary size as block size,
+	//it's computed here to avoid a different version of XZ changing the output of reproducible backups
+	let config = config!();
+	if config.block_size == 0 && config.reproducible {
+		(dict_size as u64 * 3).max(1 << 20)
 	} else {
-		path.as_ref().symlink_metadata()
+		config.block_size
 	}
 }
 
-fn compress<T>(
-	reader: PipeReader,
-	f: impl FnOnce(&mut dyn BorrowCompressor) -> io::Result<T>,
-) -> io::Result<()> {
+fn stream_builder(level: u32, chain: &[Filter], threads: u32) -> io::Result<MtStreamBuilder> {
 	let config = config!();
-	let compressor = XzEncoder::new_stream(
-		reader,
-		MtStreamBuilder::new()
-			.preset(config.level)
-			.threads(if config.threads == 0 {
-				thread::available_parallelism()?.get() as u32
-			} else {
-				config.threads
-			})
-			.block_size(config.block_size)
-			.encoder()
-			.to_io_result()?
-	);
-	if config.progress_bars {
-		static mut COMPRESSOR: OnceLock<XzEncoder<PipeReader>> = OnceLock::new();
-		// SAFETY: Only one thread has access to COMPRESSOR
-		f(unsafe {
-			let prev = COMPRESSOR.take();
-			COMPRESSOR.set(compressor).unwrap_unchecked();
-			let compressor = COMPRESSOR.get_mut().unwrap_unchecked();
-			BarsHandler::set_ticker(compressor);
-			drop(prev);
-			compressor
-		})?;
+	let mut options = LzmaOptions::new_preset(if config.extreme {
+		level | PRESET_EXTREME
 	} else {
-		f(&mut ReaderObserver(compressor))?;
+		level
+	}).to_io_result()?;
+	if config.dict_size != 0 {
+		options.dict_size(config.dict_size);
 	}
-	Ok(())
+	options
+		.literal_context_bits(config.lc)
+		.literal_position_bits(config.lp)
+		.position_bits(config.pb);
+	if config.nice_len != 0 {
+		options.nice_len(config.nice_len);
+	}
+	match config.match_finder {
+		MatchFinder::Auto => {}
+		MatchFinder::Hc3 => { options.match_finder(stream::MatchFinder::HashChain3); }
+		MatchFinder::Hc4 => { options.match_finder(stream::MatchFinder::HashChain4); }
+		MatchFinder::Bt2 => { options.match_finder(stream::MatchFinder::BinaryTree2); }
+		MatchFinder::Bt3 => { options.match_finder(stream::MatchFinder::BinaryTree3); }
+		MatchFinder::Bt4 => { options.match_finder(stream::MatchFinder::BinaryTree4); }
+	}
+	let mut filters = Filters::new();
+	for filter in chain {
+		match *filter {
+			Filter::X86 => filters.x86(),
+			Filter::PowerPc => filters.powerpc(),
+			Filter::Ia64 => filters.ia64(),
+			Filter::Arm => filters.arm(),
+			Filter::ArmThumb => filters.arm_thumb(),
+			Filter::Arm64 => filters.arm64(),
+			Filter::Sparc => filters.sparc(),
+			Filter::RiscV => filters.riscv(),
+			Filter::Delta(distance) => filters.delta_properties(&[(distance - 1) as u8]).to_io_result()?,
+			Filter::Lzma2 => filters.lzma2(&options),
+		};
+	}
+	let mut builder = MtStreamBuilder::new();
+	builder
+		.filters(filters)
+		.check(check())
+		.threads(threads)
+		.block_size(block_size(dict_size(level)));
+	Ok(builder)
 }
 
-pub fn init() -> io::Result<()> {
+//stored files still have to be in an XZ stream, but there's no point in trying hard to compress them
+fn store_builder(threads: u32) -> MtStreamBuilder {
+	let mut builder = MtStreamBuilder::new();
+	builder
+		.preset(0)
+		.check(check())
+		.threads(threads)
+		.block_size(block_size(DICT_SIZES[0]));
+	builder
+}
+
+fn check() -> Check {
+	match config!().check {
+		IntegrityCheck::None => Check::None,
+		IntegrityCheck::Crc32 => Check::Crc32,
+		IntegrityCheck::Crc64 => Check::Crc64,
+		IntegrityCheck::Sha256 => Check::Sha256,
+	}
+}
+
+//the total memory of the system, or of the cgroup the program is running in if it's smaller
+fn total_memory() -> (u64, bool) {
+	let mut system = System::new();
+	system.refresh_memory_specifics(MemoryRefreshKind::new().with_ram());
+	let total_memory = system.total_memory();
+	//it's unknown without `/proc`, and sysinfo panics when asked for cgroup limits then
+	if total_memory == 0 {
+		return (0, false);
+	}
+	match system.cgroup_limits() {
+		Some(limits) if limits.total_memory < total_memory => (limits.total_memory, true),
+		_ => (total_memory, false),
+	}
+}
+
+//returns the amount of memory XZ may use and what limits it, if anything
+fn memory_limit() -> Option<(u64, &'static str)> {
+	match config!().memory_limit {
+		MemoryLimit::Bytes(0) => match total_memory() {
+			(total_memory, true) => Some((total_memory, "the memory limit of the cgroup")),
+			_ => None,
+		},
+		MemoryLimit::Bytes(bytes) => Some((bytes, "`xz.memory_limit`")),
+		MemoryLimit::Percentage(percentage) => {
+			Some((total_memory().0 / 100 * percentage, "`xz.memory_limit`"))
+		}
+	}
+}
+
+//the memory used by an encoder with the most demanding of the filter chains
+fn memory_usage(threads: u32) -> io::Result<u64> {
+	let config = config!();
+	config.extension_filters
+		.values()
+		.chain([&config.filters])
+		.map(|chain| stream_builder(config.level, chain, threads).map(|builder| builder.memusage()))
+		.try_fold(0, |max, memory_usage| io::Result::Ok(max.max(memory_usage?)))
+}
+
+fn choose_threads() -> io::Result<u32> {
+	let config = config!();
+	//`available_parallelism` already takes cgroup CPU quotas into account
+	let available_threads = thread::available_parallelism()?.get() as u32;
+	let mut threads = match config.threads {
+		0 => available_threads,
+		threads if threads > available_threads => {
+			eprintln!(
+				"{} `{}` is {}, but only {} CPUs are available, using {} threads",
+				"warning:".yellow().bold(),
+				"xz.threads".cyan().bold(),
+				threads,
+				available_threads,
+				available_threads
+			);
+			available_threads
+		}
+		threads => threads,
+	};
+	let Some((memory_limit, name)) = memory_limit() else {
+		return Ok(threads);
+	};
+	let wanted_threads = threads;
+	while threads > 1 && memory_usage(threads)? > memory_limit {
+		threads -= 1;
+	}
+	let memory_usage = memory_usage(threads)?;
+	assert_config!(
+		memory_usage > memory_limit,
+		"XZ would use {} of memory even with a single thread, more than the {} allowed by {}",
+		HumanBytes(memory_usage),
+		HumanBytes(memory_limit),
+		name.yellow().bold()
+	);
+	if threads < wanted_threads {
+		eprintln!(
+			"{} using {} XZ threads instead of {} to stay within the {} allowed by {} ({} needed)",
+			"warning:".yellow().bold(),
+			threads,
+			wanted_threads,
+			HumanBytes(memory_limit),
+			name.cyan().bold(),
+			HumanBytes(memory_usage)
+		);
+	}
+	Ok(threads)
+}
+
+//every subarchive job has its own encoder, so there can't be more of them than the memory allows
+fn choose_jobs(xz_threads: u32) -> io::Result<u32> {
+	let config = config!();
+	if !config.use_multiple_subarchives {
+		return Ok(1);
+	}
+	let mut jobs = match config.subarchive_jobs {
+		0 => xz_threads,
+		jobs => jobs,
+	};
+	let Some((memory_limit, name)) = memory_limit() else {
+		return Ok(jobs);
+	};
+	let jobs_memory_usage = |jobs: u32| memory_usage((xz_threads / jobs).max(1)).map(|usage| usage * jobs as u64);
+	let wanted_jobs = jobs;
+	while jobs > 1 && jobs_memory_usage(jobs)? > memory_limit {
+		jobs -= 1;
+	}
+	if jobs < wanted_jobs {
+		eprintln!(
+			"{} compressing {} subarchives at the same time instead of {} to stay within the {} allowed by {} ({} needed)",
+			"warning:".yellow().bold(),
+			jobs,
+			wanted_jobs,
+			HumanBytes(memory_limit),
+			name.cyan().bold(),
+			HumanBytes(jobs_memory_usage(jobs)?)
+		);
+	}
+	Ok(jobs)
+}
+
+//compresses all of `reader` into `writer`, returning the size of the compressed data
+fn compress(reader: impl Read, builder: MtStreamBuilder, writer: &mut impl Write) -> io::Result<u64> {
+	let mut compressor = XzEncoder::new_stream(ReaderObserver(reader), builder.encoder().to_io_result()?);
+	io::copy(&mut compressor, &mut WriterObserver(writer))?;
+	Ok(compressor.total_out())
+}
+
+fn check_xz_config() -> io::Result<()> {
 	let config = config!();
 	assert_config!(
 		config.level > 9,
 		"`{}` cannot exceed 9",
 		"xz.level".yellow().bold()
 	);
+	assert_config!(
+		config.dict_size != 0 && !(4096..=1536 << 20).contains(&config.dict_size),
+		"`{}` must be between 4 KiB and 1.5 GiB",
+		"xz.dict_size".yellow().bold()
+	);
+	for (value, name) in [(config.lc, "xz.lc"), (config.lp, "xz.lp"), (config.pb, "xz.pb")] {
+		assert_config!(value > 4, "`{}` cannot exceed 4", name.yellow().bold());
+	}
+	assert_config!(
+		config.lc + config.lp > 4,
+		"the sum of `{}` and `{}` cannot exceed 4",
+		"xz.lc".yellow().bold(),
+		"xz.lp".yellow().bold()
+	);
+	assert_config!(
+		config.nice_len != 0 && !(2..=273).contains(&config.nice_len),
+		"`{}` must be between 2 and 273",
+		"xz.nice_len".yellow().bold()
+	);
+	let min_nice_len = match config.match_finder {
+		MatchFinder::Auto | MatchFinder::Bt2 => 2,
+		MatchFinder::Hc3 | MatchFinder::Bt3 => 3,
+		MatchFinder::Hc4 | MatchFinder::Bt4 => 4,
+	};
+	assert_config!(
+		config.nice_len != 0 && config.nice_len < min_nice_len,
+		"`{}` must be at least {} with the chosen `{}`",
+		"xz.nice_len".yellow().bold(),
+		min_nice_len,
+		"xz.match_finder".yellow().bold()
+	);
+	Ok(())
+}
+
+pub fn init() -> io::Result<()> {
+	let config = config!();
+	check_xz_config()?;
+	let threads = choose_threads()?;
+	let jobs = choose_jobs(threads)?;
+	assert_config!(
+		config.use_multiple_subarchives && !config.recursion,
+		"`{}` cannot be used with `{}` unless `{}` is used too",
+		"backup.use_multiple_subarchives".yellow().bold(),
+		"--files-from".yellow().bold(),
+		"--recursion".yellow().bold()
+	);
+	assert_config!(
+		config.subarchive_max_size != 0 && config.subarchive_min_size > config.subarchive_max_size,
+		"`{}` cannot be bigger than `{}`",
+		"backup.subarchive_min_size".yellow().bold(),
+		"backup.subarchive_max_size".yellow().bold()
+	);
+	assert_config!(
+		config.preserve_xattrs && config.tar_format == TarFormat::Ustar,
+		"`{}` cannot be used with the ustar format",
+		"backup.preserve_xattrs".yellow().bold()
+	);
 	let path_name = Path::new(&config.name);
 	let mut output_file = if config.force_overwrite {
 		File::create(path_name)?
@@ -117,39 +386,57 @@ pub fn init() -> io::Result<()> {
 	}
 	let output_file_id = output_file.get_id()?;
 	BarsHandler::init(output_file_id)?;
-	if config.use_multiple_subarchives {
-		let tar_thread = tar::spawn_thread(output_file, output_file_id);
-		loop {
-			thread::park();
-			// SAFETY: The tar thread will drop the values only after this thread unparks it.
-			let subarchive_values = unsafe {
-				if SUBARCHIVE_VALUES.is_null() {
-					break tar_thread;
-				} else {
-					SUBARCHIVE_VALUES.deref()
-				}
-			};
-			compress(
-				subarchive_values.reader.try_clone()?,
-				|compressor| {
-					unsafe {
-						(subarchive_values.f)(
-							compressor,
-							&*subarchive_values.dir_path,
-							&*subarchive_values.name_start,
-							&mut *subarchive_values.builder,
-						)
-					}
-				}
-			)?;
-			tar_thread.thread().unpark();
-		}
+	//set when compressing fails, so that the tar thread stops before the error is returned
+	let cancelled = Arc::new(AtomicBool::new(false));
+	let (tar_thread, result) = if config.use_multiple_subarchives {
+		//the tar thread sends back the subarchives to compress and appends them once compressed
+		let (job_sender, job_receiver) = mpsc::channel();
+		let tar_thread = tar::spawn_subarchives_thread(
+			output_file,
+			output_file_id,
+			job_sender,
+			threads,
+			jobs,
+			cancelled.clone()
+		);
+		let result = job_receiver.into_iter().try_for_each(|mut job| {
+			let result = stream_builder(config.level, job.filters, threads)
+				.and_then(|builder| compress(&job.reader, builder, &mut job.writer));
+			if result.is_err() {
+				//cancelled before the reader is dropped, so that the archiver doesn't report the broken pipe
+				cancelled.store(true, Ordering::Relaxed);
+			}
+			result.map(drop)
+		});
+		(tar_thread, result)
 	} else {
 		let (reader, writer) = os_pipe::pipe()?;
-		let tar_thread = tar::spawn_thread(writer, output_file_id);
-		compress(reader, |compressor| io::copy(compressor, &mut output_file))?;
-		tar_thread
-	}.join().unwrap();
+		let (sender, receiver) = mpsc::channel();
+		let tar_thread = tar::spawn_thread(
+			StoreWriter::new(writer, StoreMode::Framed(sender)),
+			output_file_id,
+			cancelled.clone()
+		);
+		//every group of stored files gets its own XZ stream, XZ decompresses concatenated streams as one
+		let reader = SegmentReader::new(reader, receiver);
+		let result = loop {
+			let builder = if reader.stored() {
+				Ok(store_builder(threads))
+			} else {
+				stream_builder(config.level, &config.filters, threads)
+			};
+			if let Err(e) = builder.and_then(|builder| compress(reader.clone(), builder, &mut output_file)) {
+				cancelled.store(true, Ordering::Relaxed);
+				break Err(e);
+			}
+			if !reader.next_segment() {
+				break Ok(());
+			}
+		};
+		(tar_thread, result)
+	};
+	tar_thread.join().unwrap();
+	result?;
 	BarsHandler::end(|bars_handler| {
 		bars_handler.status_bar.inc(1);
 		bars_handler.status_bar.finish_with_message(format!(
@@ -0,0 +1,282 @@
+use std::{fs::{self, File, Metadata}, io::{self, Write}, path::{Path, PathBuf}, time::UNIX_EPOCH};
+use colored::Colorize;
+use crate::config::{config, TarFormat};
+use super::metadata;
+use tar::{Builder, EntryType, Header};
+
+pub type Records = Vec<(String, Vec<u8>)>;
+
+const MAX_OCTAL_SIZE: u64 = 0o77777777777;
+const MAX_OCTAL_ID: u64 = 0o7777777;
+
+fn path_bytes(path: &Path) -> Vec<u8> {
+	let mut bytes = Vec::new();
+	for component in path.components() {
+		if !bytes.is_empty() {
+			bytes.push(b'/');
+		}
+		bytes.extend_from_slice(component.as_os_str().as_encoded_bytes());
+	}
+	bytes
+}
+
+fn fallback_name(path: &Path) -> PathBuf {
+	let name = path
+		.file_name()
+		.map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+		.chars()
+		.filter(|c| c.is_ascii_graphic() && *c != '/' && *c != '\\')
+		.take(99)
+		.collect::<String>();
+	PathBuf::from(if name.is_empty() || name == ".." { String::from("PaxEntry") } else { name })
+}
+
+fn set_name(
+	header: &mut Header,
+	records: &mut Records,
+	key: &str,
+	name: &Path,
+	set: fn(&mut Header, &Path) -> io::Result<()>,
+) -> io::Result<()> {
+	let result = set(header, name);
+	if *config!(tar_format) == TarFormat::Ustar {
+		return result;
+	}
+	let bytes = path_bytes(name);
+	if result.is_err() || !bytes.is_ascii() {
+		records.push((key.to_string(), bytes));
+		set(header, &fallback_name(name))?;
+	}
+	Ok(())
+}
+
+pub fn set_path(header: &mut Header, records: &mut Records, path: &Path) -> io::Result<()> {
+	set_name(header, records, "path", path, |header, path| header.set_path(path))
+}
+
+pub fn set_link_name(header: &mut Header, records: &mut Records, link_name: &Path) -> io::Result<()> {
+	set_name(header, records, "linkpath", link_name, |header, link_name| header.set_link_name(link_name))
+}
+
+fn set_number(
+	records: &mut Records,
+	key: &str,
+	value: u64,
+	max: u64,
+	set: impl FnOnce(u64),
+) -> io::Result<()> {
+	if value <= max {
+		return Ok(());
+	}
+	if *config!(tar_format) == TarFormat::Ustar {
+		return Err(io::Error::other(format!("{key} is too big to be stored in the ustar format")));
+	}
+	records.push((key.to_string(), value.to_string().into_bytes()));
+	set(0);
+	Ok(())
+}
+
+#[cfg(unix)]
+fn set_owner_names(header: &mut Header, uid: u64, gid: u64) -> io::Result<()> {
+	use std::{collections::HashMap, sync::OnceLock};
+	use sysinfo::{Groups, Users};
+	static OWNER_NAMES: OnceLock<(HashMap<u64, String>, HashMap<u64, String>)> = OnceLock::new();
+	let (user_names, group_names) = OWNER_NAMES.get_or_init(|| (
+		Users::new_with_refreshed_list()
+			.iter()
+			.map(|user| (**user.id() as u64, user.name().to_string()))
+			.collect(),
+		Groups::new_with_refreshed_list()
+			.iter()
+			.map(|group| (**group.id() as u64, group.name().to_string()))
+			.collect(),
+	));
+	if let Some(name) = user_names.get(&uid).filter(|name| name.len() <= 32) {
+		header.set_username(name)?;
+	}
+	if let Some(name) = group_names.get(&gid).filter(|name| name.len() <= 32) {
+		header.set_groupname(name)?;
+	}
+	Ok(())
+}
+
+#[cfg(windows)]
+fn set_owner_names(_: &mut Header, _: u64, _: u64) -> io::Result<()> {
+	Ok(())
+}
+
+pub fn set_metadata(header: &mut Header, meta: &Metadata) -> io::Result<()> {
+	let config = config!();
+	header.set_metadata(meta);
+	let (uid, gid) = (header.uid()?, header.gid()?);
+	let uid = config.owner_map.get(&uid).copied().unwrap_or(uid);
+	let gid = config.group_map.get(&gid).copied().unwrap_or(gid);
+	header.set_uid(uid);
+	header.set_gid(gid);
+	if !config.numeric_owner {
+		set_owner_names(header, uid, gid)?;
+	}
+	if config.reproducible {
+		header.set_uid(0);
+		header.set_gid(0);
+		header.set_username("")?;
+		header.set_groupname("")?;
+	}
+	if let Some(clamp_mtime) = config.clamp_mtime {
+		header.set_mtime(header.mtime()?.min(clamp_mtime));
+	}
+	Ok(())
+}
+
+fn set_numbers(header: &mut Header, records: &mut Records, meta: &Metadata) -> io::Result<()> {
+	let (size, uid, gid) = (header.entry_size()?, header.uid()?, header.gid()?);
+	set_number(records, "size", size, MAX_OCTAL_SIZE, |size| header.set_size(size))?;
+	set_number(records, "uid", uid, MAX_OCTAL_ID, |uid| header.set_uid(uid))?;
+	set_number(records, "gid", gid, MAX_OCTAL_ID, |gid| header.set_gid(gid))?;
+	if *config!(tar_format) == TarFormat::Pax {
+		if let Ok(mtime) = meta.modified()?.duration_since(UNIX_EPOCH) {
+			let clamped = config!(clamp_mtime).is_some_and(|clamp_mtime| mtime.as_secs() >= clamp_mtime);
+			if mtime.subsec_nanos() != 0 && !clamped {
+				let mtime = format!("{}.{:09}", mtime.as_secs(), mtime.subsec_nanos());
+				records.push((String::from("mtime"), mtime.trim_end_matches('0').as_bytes().to_vec()));
+			}
+		}
+	}
+	Ok(())
+}
+
+#[cfg(unix)]
+pub fn set_device(header: &mut Header, meta: &Metadata) -> io::Result<()> {
+	use std::os::unix::fs::{FileTypeExt, MetadataExt};
+	let file_type = meta.file_type();
+	if file_type.is_socket() {
+		return Err(io::Error::other("sockets cannot be archived"));
+	}
+	if file_type.is_char_device() || file_type.is_block_device() {
+		let dev_id = meta.rdev();
+		let dev_major = ((dev_id >> 32) & 0xffff_f000) | ((dev_id >> 8) & 0x0000_0fff);
+		let dev_minor = ((dev_id >> 12) & 0xffff_ff00) | (dev_id & 0x0000_00ff);
+		header.set_device_major(dev_major as u32)?;
+		header.set_device_minor(dev_minor as u32)?;
+	}
+	Ok(())
+}
+
+#[cfg(windows)]
+pub fn set_device(_: &mut Header, _: &Metadata) -> io::Result<()> {
+	Ok(())
+}
+
+pub fn append_entry<W: Write>(
+	builder: &mut Builder<W>,
+	path: &Path,
+	name: &Path,
+	mut records: Records,
+) -> io::Result<()> {
+	let meta = metadata(path)?;
+	let file = if meta.is_file() { Some(File::open(path)?) } else { None };
+	let mut header = Header::new_ustar();
+	set_metadata(&mut header, &meta)?;
+	set_path(&mut header, &mut records, name)?;
+	if meta.is_symlink() {
+		set_link_name(&mut header, &mut records, &fs::read_link(path)?)?;
+	}
+	set_device(&mut header, &meta)?;
+	set_numbers(&mut header, &mut records, &meta)?;
+	header.set_cksum();
+	append_extensions(builder, &records)?;
+	match file {
+		Some(file) => builder.append(&header, file),
+		None => builder.append(&header, io::empty()),
+	}
+}
+
+pub fn append_link<W: Write>(
+	builder: &mut Builder<W>,
+	path: &Path,
+	name: &Path,
+	target: &Path,
+) -> io::Result<()> {
+	let meta = metadata(path)?;
+	let mut records = Vec::new();
+	let mut header = Header::new_ustar();
+	set_metadata(&mut header, &meta)?;
+	header.set_entry_type(EntryType::Link);
+	header.set_size(0);
+	set_path(&mut header, &mut records, name)?;
+	set_link_name(&mut header, &mut records, target)?;
+	set_numbers(&mut header, &mut records, &meta)?;
+	header.set_cksum();
+	append_extensions(builder, &records)?;
+	builder.append(&header, io::empty())
+}
+
+fn record_len(key: &str, value: &[u8]) -> usize {
+	let base = key.len() + value.len() + 3;
+	let mut len = base + 1;
+	loop {
+		let new_len = base + len.to_string().len();
+		if new_len == len {
+			break len;
+		}
+		len = new_len;
+	}
+}
+
+pub fn append_extensions<W: Write>(builder: &mut Builder<W>, records: &Records) -> io::Result<()> {
+	if records.is_empty() {
+		return Ok(());
+	}
+	let mut data = Vec::new();
+	for (key, value) in records {
+		write!(data, "{} {key}=", record_len(key, value))?;
+		data.extend_from_slice(value);
+		data.push(b'\n');
+	}
+	let mut header = Header::new_ustar();
+	header.set_path("././@PaxHeader")?;
+	header.set_entry_type(EntryType::XHeader);
+	header.set_mode(0o644);
+	header.set_size(data.len() as u64);
+	header.set_cksum();
+	builder.append(&header, data.as_slice())
+}
+
+#[cfg(unix)]
+pub fn xattr_records(path: &Path) -> io::Result<Records> {
+	let follow_symlinks = config!().follow_symlinks();
+	let names = match if follow_symlinks { xattr::list_deref(path) } else { xattr::list(path) } {
+		Ok(names) => names,
+		Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(Vec::new()),
+		Err(e) => return Err(e),
+	};
+	let mut records = Vec::new();
+	for name in names {
+		let value = if follow_symlinks {
+			xattr::get_deref(path, &name)
+		} else {
+			xattr::get(path, &name)
+		}?;
+		let Some(value) = value else {
+			continue;
+		};
+		//pax records are UTF-8, a lossy conversion would restore the value under another name
+		let Some(name) = name.to_str() else {
+			eprintln!(
+				"{} skipping the extended attribute `{}` of `{}` (its name is not valid UTF-8)",
+				"warning:".yellow().bold(),
+				name.to_string_lossy().cyan().bold(),
+				path.to_string_lossy().cyan().bold(),
+			);
+			continue;
+		};
+		records.push((format!("SCHILY.xattr.{name}"), value));
+	}
+	records.sort();
+	Ok(records)
+}
+
+#[cfg(windows)]
+pub fn xattr_records(_: &Path) -> io::Result<Records> {
+	Ok(Vec::new())
+}
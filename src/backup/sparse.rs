@@ -0,0 +1,139 @@
+use std::{fs::{File, Metadata}, io::{self, Read, Seek, SeekFrom}, vec};
+use crate::config::config;
+use tar::{EntryType, GnuExtSparseHeader, Header};
+
+struct Segment {
+	offset: u64,
+	len: u64,
+}
+
+#[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux"))]
+fn find_segments(file: &File, meta: &Metadata) -> io::Result<Option<Vec<Segment>>> {
+	use std::os::{fd::AsRawFd, unix::fs::MetadataExt};
+	#[cfg(any(target_os = "android", target_os = "linux"))]
+	use libc::lseek64 as lseek;
+	#[cfg(target_os = "freebsd")]
+	use libc::lseek;
+
+	fn seek(file: &File, offset: u64, whence: libc::c_int) -> io::Result<Option<u64>> {
+		// SAFETY: The file descriptor stays valid for as long as `file` is borrowed.
+		match unsafe { lseek(file.as_raw_fd(), offset as i64, whence) } {
+			-1 => match io::Error::last_os_error() {
+				e if e.raw_os_error() == Some(libc::ENXIO) => Ok(None),
+				e => Err(e),
+			},
+			offset => Ok(Some(offset as u64)),
+		}
+	}
+
+	let len = meta.len();
+	if meta.blocks() * 512 >= len {
+		return Ok(None);
+	}
+	let mut segments = Vec::new();
+	let mut offset = 0;
+	while offset < len {
+		let start = match seek(file, offset, libc::SEEK_DATA) {
+			Ok(Some(start)) if start < len => start,
+			Ok(_) => break,
+			Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+			Err(e) => return Err(e),
+		};
+		let end = seek(file, start, libc::SEEK_HOLE)?.map_or(len, |end| end.min(len));
+		if end <= start {
+			break;
+		}
+		segments.push(Segment { offset: start, len: end - start });
+		offset = end;
+	}
+	if offset == len && segments.len() == 1 && segments[0].offset == 0 {
+		return Ok(None);
+	}
+	if offset < len {
+		segments.push(Segment { offset: len, len: 0 });
+	}
+	Ok(Some(segments))
+}
+
+#[cfg(not(any(target_os = "android", target_os = "freebsd", target_os = "linux")))]
+fn find_segments(_: &File, _: &Metadata) -> io::Result<Option<Vec<Segment>>> {
+	Ok(None)
+}
+
+pub struct FileData {
+	ext_headers: io::Cursor<Vec<u8>>,
+	file: File,
+	segments: vec::IntoIter<Segment>,
+	remaining: u64,
+}
+
+impl Read for FileData {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let read = self.ext_headers.read(buf)?;
+		if read > 0 {
+			return Ok(read);
+		}
+		while self.remaining == 0 {
+			let Some(segment) = self.segments.next() else {
+				return Ok(0);
+			};
+			self.file.seek(SeekFrom::Start(segment.offset))?;
+			self.remaining = segment.len;
+		}
+		let len = self.remaining.min(buf.len() as u64) as usize;
+		let buf = &mut buf[..len];
+		let read = match self.file.read(buf)? {
+			// The file shrank while being archived, pad it to the size written in the header.
+			0 => {
+				buf.fill(0);
+				buf.len()
+			}
+			read => read,
+		};
+		self.remaining -= read as u64;
+		Ok(read)
+	}
+}
+
+/// Prepares the header of a regular file, turning it into a GNU sparse entry if `backup.sparse` is
+/// enabled and the file has holes, and returns the data that has to follow the header.
+pub fn prepare_file(header: &mut Header, file: File, meta: &Metadata) -> io::Result<FileData> {
+	let segments = if *config!(sparse) {
+		find_segments(&file, meta)?
+	} else {
+		None
+	};
+	let mut ext_headers = Vec::new();
+	let segments = match segments {
+		Some(segments) => {
+			header.set_entry_type(EntryType::GNUSparse);
+			header.set_size(segments.iter().map(|segment| segment.len).sum());
+			let gnu_header = header.as_gnu_mut().unwrap();
+			gnu_header.set_real_size(meta.len());
+			for (segment, sparse) in segments.iter().zip(&mut gnu_header.sparse) {
+				sparse.set_offset(segment.offset);
+				sparse.set_length(segment.len);
+			}
+			let in_header = gnu_header.sparse.len();
+			gnu_header.set_is_extended(segments.len() > in_header);
+			let mut chunks = segments[in_header.min(segments.len())..].chunks(21).peekable();
+			while let Some(chunk) = chunks.next() {
+				let mut ext_header = GnuExtSparseHeader::new();
+				for (segment, sparse) in chunk.iter().zip(&mut ext_header.sparse) {
+					sparse.set_offset(segment.offset);
+					sparse.set_length(segment.len);
+				}
+				ext_header.set_is_extended(chunks.peek().is_some());
+				ext_headers.extend_from_slice(ext_header.as_bytes());
+			}
+			segments
+		}
+		None => vec![Segment { offset: 0, len: header.entry_size()? }],
+	};
+	Ok(FileData {
+		ext_headers: io::Cursor::new(ext_headers),
+		file,
+		segments: segments.into_iter(),
+		remaining: 0,
+	})
+}
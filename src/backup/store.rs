@@ -0,0 +1,237 @@
+use std::{
+	cell::RefCell,
+	collections::VecDeque,
+	fs::File,
+	io::{self, Read, Write},
+	path::{Path, PathBuf},
+	rc::Rc,
+	sync::mpsc::{Receiver, Sender},
+};
+use crate::config::config;
+use super::metadata;
+use os_pipe::{PipeReader, PipeWriter};
+
+//storing small files isn't worth splitting the compressed stream
+const MIN_STORED_SIZE: u64 = 64 * 1024;
+
+const MAGIC_NUMBERS: [(usize, &[u8]); 21] = [
+	(0, b"\x1f\x8b"),                     //gzip
+	(0, b"\xfd7zXZ\x00"),                 //xz
+	(0, b"\x5d\x00\x00"),                 //lzma
+	(0, b"BZh"),                          //bzip2
+	(0, b"\x28\xb5\x2f\xfd"),             //zstd
+	(0, b"\x04\x22\x4d\x18"),             //lz4
+	(0, b"PK\x03\x04"),                   //zip, jar, docx, apk...
+	(0, b"7z\xbc\xaf\x27\x1c"),           //7z
+	(0, b"Rar!\x1a\x07"),                 //rar
+	(0, b"\xff\xd8\xff"),                 //jpeg
+	(0, b"\x89PNG"),                      //png
+	(0, b"GIF8"),                         //gif
+	(8, b"WEBP"),                         //webp
+	(4, b"ftyp"),                         //mp4, mov, heic...
+	(0, b"\x1a\x45\xdf\xa3"),             //mkv, webm
+	(0, b"ID3"),                          //mp3
+	(0, b"\xff\xfb"),                     //mp3
+	(0, b"OggS"),                         //ogg, opus
+	(0, b"fLaC"),                         //flac
+	(0, b"wOF2"),                         //woff2
+	(0, b"%PDF"),                         //pdf
+];
+
+fn is_compressed(path: &Path) -> bool {
+	let mut buf = [0; 12];
+	let Ok(len) = File::open(path).and_then(|mut file| file.read(&mut buf)) else {
+		return false;
+	};
+	let buf = &buf[..len];
+	MAGIC_NUMBERS
+		.iter()
+		.any(|(offset, magic)| buf.get(*offset..offset + magic.len()) == Some(magic))
+}
+
+/// Checks whether a file should be stored without being compressed, either because of its
+/// extension being in `backup.store_extensions` or because of its contents if
+/// `backup.detect_compressed` is enabled.
+pub fn is_stored(path: &Path) -> bool {
+	let config = config!();
+	if config.store_extensions.is_empty() && !config.detect_compressed {
+		return false;
+	}
+	if !metadata(path).is_ok_and(|meta| meta.is_file() && meta.len() >= MIN_STORED_SIZE) {
+		return false;
+	}
+	path.extension().is_some_and(|extension| {
+		config.store_extensions.contains(&extension.to_ascii_lowercase())
+	}) || config.detect_compressed && is_compressed(path)
+}
+
+/// Destination of the entries archived by the tar thread.
+pub trait EntryWriter : Write {
+	/// Called before archiving each entry, returns `false` if the entry should be set aside to be
+	/// archived outside of the current archive.
+	fn start_entry(&mut self, _stored: bool) -> bool {
+		true
+	}
+}
+
+impl EntryWriter for File {}
+
+pub enum StoreMode {
+	/// Tell the compressor where stored entries start and end.
+	Framed(Sender<(u64, bool)>),
+
+	/// Set stored entries aside to be archived in the outer archive.
+	SetAside,
+}
+
+pub struct StoreWriter {
+	writer: PipeWriter,
+	mode: StoreMode,
+	written: u64,
+	stored: Option<bool>,
+}
+
+impl StoreWriter {
+	pub fn new(writer: PipeWriter, mode: StoreMode) -> Self {
+		Self {
+			writer,
+			mode,
+			written: 0,
+			stored: None,
+		}
+	}
+}
+
+impl Write for StoreWriter {
+	fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+		let written = self.writer.write(buf)?;
+		self.written += written as u64;
+		Ok(written)
+	}
+
+	fn flush(&mut self) -> io::Result<()> {
+		self.writer.flush()
+	}
+}
+
+impl EntryWriter for StoreWriter {
+	fn start_entry(&mut self, stored: bool) -> bool {
+		match &self.mode {
+			StoreMode::Framed(sender) => {
+				if self.stored != Some(stored) {
+					self.stored = Some(stored);
+					//the compressor will stop reading at the boundary only if it knows about it
+					//before the bytes following it are written
+					let _ = sender.send((self.written, stored));
+				}
+				true
+			}
+			StoreMode::SetAside => !stored,
+		}
+	}
+}
+
+struct SegmentState {
+	reader: PipeReader,
+	boundaries: Receiver<(u64, bool)>,
+	next_boundary: Option<(u64, bool)>,
+	pending: VecDeque<u8>,
+	position: u64,
+	stored: bool,
+}
+
+impl SegmentState {
+	fn poll_boundary(&mut self) {
+		if self.next_boundary.is_none() {
+			self.next_boundary = self.boundaries.try_recv().ok();
+		}
+	}
+}
+
+/// Splits the archive written by a [`StoreWriter`] into segments of entries that are either all
+/// stored or all compressed, every clone reads from the same segment.
+#[derive(Clone)]
+pub struct SegmentReader(Rc<RefCell<SegmentState>>);
+
+impl SegmentReader {
+	pub fn new(reader: PipeReader, boundaries: Receiver<(u64, bool)>) -> Self {
+		//the first boundary tells whether the archive starts with stored entries
+		let stored = match boundaries.recv() {
+			Ok((_, stored)) => stored,
+			Err(_) => false,
+		};
+		Self(Rc::new(RefCell::new(SegmentState {
+			reader,
+			boundaries,
+			next_boundary: None,
+			pending: VecDeque::new(),
+			position: 0,
+			stored,
+		})))
+	}
+
+	/// Whether the entries in the current segment are stored.
+	pub fn stored(&self) -> bool {
+		self.0.borrow().stored
+	}
+
+	/// Moves to the next segment, returns `false` if the whole archive was read.
+	pub fn next_segment(&self) -> bool {
+		let mut state = self.0.borrow_mut();
+		state.poll_boundary();
+		match state.next_boundary.take() {
+			Some((position, stored)) if position == state.position => {
+				state.stored = stored;
+				true
+			}
+			next_boundary => {
+				state.next_boundary = next_boundary;
+				false
+			}
+		}
+	}
+}
+
+impl Read for SegmentReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let state = &mut *self.0.borrow_mut();
+		state.poll_boundary();
+		let limit = match state.next_boundary {
+			Some((boundary, _)) => (boundary - state.position).min(buf.len() as u64) as usize,
+			None => buf.len(),
+		};
+		let buf = &mut buf[..limit];
+		let read = if state.pending.is_empty() {
+			state.reader.read(buf)?
+		} else {
+			state.pending.read(buf)?
+		};
+		state.poll_boundary();
+		let read = match state.next_boundary {
+			Some((boundary, _)) if state.position + read as u64 > boundary => {
+				let end = (boundary - state.position) as usize;
+				for byte in buf[end..read].iter().rev() {
+					state.pending.push_front(*byte);
+				}
+				end
+			}
+			_ => read,
+		};
+		state.position += read as u64;
+		Ok(read)
+	}
+}
+
+thread_local! {
+	static SET_ASIDE: RefCell<Vec<(PathBuf, PathBuf)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Remembers an entry that was set aside by a [`StoreWriter`].
+pub fn set_aside(path: &Path, name: &Path) {
+	SET_ASIDE.with_borrow_mut(|set_aside| set_aside.push((path.to_path_buf(), name.to_path_buf())));
+}
+
+/// Returns every entry set aside since the last call.
+pub fn take_set_aside() -> Vec<(PathBuf, PathBuf)> {
+	SET_ASIDE.take()
+}
@@ -1,14 +1,28 @@
 use std::{
-	fs::{DirEntry, File},
-	io::{self, Seek, SeekFrom, Write},
-	path::{Path, PathBuf},
-	ptr, thread::{self, JoinHandle}
+	collections::{HashMap, HashSet, VecDeque},
+	cell::RefCell,
+	error::Error,
+	ffi::{OsStr, OsString},
+	fmt::{self, Display},
+	fs::{self, DirEntry, File, Metadata},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::{Component, Path, PathBuf},
+	process,
+	sync::{atomic::{AtomicBool, Ordering}, mpsc::Sender, Arc},
+	thread::{self, JoinHandle}
+};
+use crate::{config::{bytes_to_path, Filter, PathConfig, SortOrder, TagKeepMode, TarFormat, config}, error::ResultExt, input};
+use super::{
+	bars::BarsHandler,
+	chunks::{self, ChunkWriter},
+	metadata,
+	pax,
+	sparse,
+	store::{self, EntryWriter, StoreMode, StoreWriter},
 };
-use crate::{config::{TagKeepMode, config}, error::ResultExt, input, static_ptr::StaticPointer};
-use super::{bars::BarsHandler, metadata, BorrowCompressor};
 use colored::Colorize;
 use fs_id::{FileID, GetID};
-use os_pipe::PipeReader;
+use os_pipe::{PipeReader, PipeWriter};
 use tar::{Builder, EntryType, Header};
 
 macro_rules! try_access {
@@ -28,17 +42,55 @@ macro_rules! try_access {
 
 use try_access;
 
-pub struct SubarchiveValues {
+/// A subarchive for the compressor to compress, the result is sent back to the tar thread.
+pub struct SubarchiveJob {
 	pub reader: PipeReader,
-	pub dir_path: *const PathBuf,
-	pub name_start: *const Option<PathBuf>,
-	pub builder: *mut Builder<File>,
-	pub f: fn(&mut dyn BorrowCompressor, &PathBuf, &Option<PathBuf>, &mut Builder<File>) -> io::Result<()>
+	pub filters: &'static [Filter],
+	pub writer: ChunkWriter,
+}
+
+#[derive(Debug)]
+struct FilesystemLoop;
+
+impl Display for FilesystemLoop {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str("directory was already entered, following it would cause a loop")
+	}
 }
 
-pub static mut SUBARCHIVE_VALUES: StaticPointer<SubarchiveValues> = StaticPointer::null();
+impl Error for FilesystemLoop {}
+
+thread_local! {
+	static CANCELLED: RefCell<Option<Arc<AtomicBool>>> = const { RefCell::new(None) };
+}
+
+//makes `scan_path` stop in the current thread once `cancelled` is set
+fn stop_when(cancelled: Arc<AtomicBool>) {
+	CANCELLED.set(Some(cancelled));
+}
+
+//the flag set with `stop_when`, or a new one if there's none
+fn cancellation() -> Arc<AtomicBool> {
+	CANCELLED.with_borrow(|cancelled| cancelled.clone().unwrap_or_default())
+}
+
+fn is_cancelled() -> bool {
+	CANCELLED.with_borrow(|cancelled| cancelled.as_ref().is_some_and(|cancelled| cancelled.load(Ordering::Relaxed)))
+}
 
 fn failed_access(path: &Path, e: &io::Error) -> bool {
+	//whatever failed after cancelling is caused by it, it's not worth asking about
+	if is_cancelled() {
+		return true;
+	}
+	if e.get_ref().is_some_and(|e| e.is::<FilesystemLoop>()) {
+		eprintln!(
+			"{} skipping `{}` ({e})",
+			"warning:".yellow().bold(),
+			path.to_string_lossy().cyan().bold(),
+		);
+		return true;
+	}
 	let mut ignore = config!(ignore_unreadable_files).lock().unwrap();
 	if *ignore {
 		return true;
@@ -74,7 +126,7 @@ fn get_dir_contents(
 	let mut keep_tag = false;
 	for entry in try_access!(path.read_dir()) {
 		let entry = try_access!(entry);
-		if let Some(mode) = config!(exclude_tags).get(&entry.file_name()).copied() {
+		if let Some(mode) = config!().exclude_tags().get(&entry.file_name()).copied() {
 			if mode == TagKeepMode::None {
 				return None;
 			}
@@ -87,15 +139,79 @@ fn get_dir_contents(
 		}
 		contents.push(entry);
 	}
+	match *config!(sort_order) {
+		SortOrder::Natural => if *config!(reproducible) {
+			contents.sort_by_key(DirEntry::file_name);
+		},
+		//the name is used as a tie breaker to keep reproducible backups reproducible
+		SortOrder::Extension => contents.sort_by_cached_key(|entry| {
+			let name = entry.file_name();
+			(Path::new(&name).extension().map(OsStr::to_ascii_lowercase), name)
+		}),
+		SortOrder::Size => contents.sort_by_cached_key(|entry| (
+			metadata(entry.path()).map_or(0, |meta| if meta.is_file() { meta.len() } else { 0 }),
+			entry.file_name(),
+		)),
+	}
 	Some((contents, keep_tag))
 }
 
+#[cfg(windows)]
+fn get_file_id(path: &Path) -> io::Result<FileID> {
+	use std::os::windows::fs::OpenOptionsExt;
+	const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+	File::options()
+		.read(true)
+		.custom_flags(FILE_FLAG_BACKUP_SEMANTICS)
+		.open(path)?
+		.get_id()
+}
+
+#[cfg(unix)]
+fn get_file_id(path: &Path) -> io::Result<FileID> {
+	path.get_id()
+}
+
+#[cfg(windows)]
+fn has_links(_: &Metadata) -> bool {
+	true
+}
+
+#[cfg(unix)]
+fn has_links(meta: &Metadata) -> bool {
+	use std::os::unix::fs::MetadataExt;
+	meta.nlink() > 1
+}
+
+//the filesystems of the paths to backup, found once per configuration
+fn allowed_storage_ids() -> &'static HashSet<u64> {
+	let config = config!();
+	config.allowed_storage_ids.get_or_init(|| {
+		config.paths
+			.iter()
+			.chain(&config.allowed_file_systems)
+			.filter_map(|path| get_file_id(path).ok())
+			.map(|id| id.storage_id())
+			.collect()
+	})
+}
+
+//whether the contents of a directory can be archived, the ones that can't are archived empty
+fn can_enter(path: &Path) -> bool {
+	let config = config!();
+	if !config.recursion || config.excluded_mount_points.contains(path) {
+		return false;
+	}
+	!config.one_file_system || get_file_id(path).is_ok_and(|id| allowed_storage_ids().contains(&id.storage_id()))
+}
+
 fn scan_path_internal(
-	output_file_id: FileID,
+	excluded_files: &[FileID],
 	path: PathBuf,
 	name: PathBuf,
+	ancestors: &mut Vec<FileID>,
 	failed_access: fn(&Path, &io::Error) -> bool,
-	action: &mut impl FnMut(&PathBuf, &PathBuf) -> io::Result<()>,
+	action: &mut impl FnMut(&PathBuf, &PathBuf, Option<FileID>) -> io::Result<()>,
 ) {
 	macro_rules! try_access {
 		($f:expr) => {
@@ -103,28 +219,75 @@ fn scan_path_internal(
 		};
 	}
 
+	if is_cancelled() {
+		return;
+	}
 	let config = config!();
+	let follow_symlinks = config.follow_symlinks();
 	let meta = try_access!(metadata(&path));
-	if meta.is_dir() && (config.follow_symlinks || !meta.is_symlink()) {
+	if meta.is_dir() && (follow_symlinks || !meta.is_symlink()) {
+		if !config.recursion || config.excluded_mount_points.contains(&path) {
+			try_access!(action(&path, &name, None));
+			return;
+		}
+		let dir_id = if config.one_file_system || follow_symlinks {
+			Some(try_access!(get_file_id(&path)))
+		} else {
+			None
+		};
+		if let Some(dir_id) = dir_id {
+			if config.one_file_system && !allowed_storage_ids().contains(&dir_id.storage_id()) {
+				try_access!(action(&path, &name, None));
+				return;
+			}
+			if ancestors.contains(&dir_id) {
+				failed_access(&path, &io::Error::other(FilesystemLoop));
+				return;
+			}
+		}
 		let Some((contents, keep_tag)) = get_dir_contents(&path, &failed_access) else {
 			return;
 		};
-		try_access!(action(&path, &name));
+		try_access!(action(&path, &name, None));
 		let scan_func = if keep_tag { scan_path_internal } else { scan_path };
+		if follow_symlinks {
+			ancestors.extend(dir_id);
+		}
 		for entry in contents {
 			let entry_path = entry.path().to_path_buf();
-			scan_func(output_file_id, entry_path, name.join(entry.file_name()), failed_access, action);
+			if config.nested_paths.contains(&entry_path) {
+				continue;
+			}
+			scan_func(
+				excluded_files,
+				entry_path,
+				name.join(entry.file_name()),
+				ancestors,
+				failed_access,
+				action
+			);
 		}
-	} else {
-		if output_file_id == try_access!(path.get_id()) {
-			return;
+		if follow_symlinks {
+			ancestors.pop();
 		}
-		try_access!(action(&path, &name));
+	} else {
+		//only the files that can be reached again are given their id, to be archived as hard links then,
+		//paths to backup never overlap so a file can only be reached twice through links
+		let file_id = if meta.is_file() {
+			let file_id = try_access!(path.get_id());
+			if excluded_files.contains(&file_id) {
+				return;
+			}
+			(follow_symlinks || has_links(&meta)).then_some(file_id)
+		} else {
+			None
+		};
+		try_access!(action(&path, &name, file_id));
 	}
 }
 
 fn is_excluded(path: &[u8]) -> bool {
-	for pattern in config!(exclude) {
+	for pattern in config!().exclude() {
 		if pattern.is_match(path) {
 			return true;
 		}
@@ -133,21 +296,22 @@ fn is_excluded(path: &[u8]) -> bool {
 }
 
 pub fn scan_path(
-	output_file_id: FileID,
+	excluded_files: &[FileID],
 	path: PathBuf,
 	name: PathBuf,
+	ancestors: &mut Vec<FileID>,
 	failed_access: fn(&Path, &io::Error) -> bool,
-	action: &mut impl FnMut(&PathBuf, &PathBuf) -> io::Result<()>,
+	action: &mut impl FnMut(&PathBuf, &PathBuf, Option<FileID>) -> io::Result<()>,
 ) {
 	if is_excluded(path.as_os_str().as_encoded_bytes()) {
 		return
 	}
-	scan_path_internal(output_file_id, path, name, failed_access, action)
+	scan_path_internal(excluded_files, path, name, ancestors, failed_access, action)
 }
 
 #[cfg(windows)]
-fn get_name(path: &Path, name_start: &Option<PathBuf>) -> PathBuf {
-	let name = match path.file_name() {
+fn get_file_name(path: &Path) -> PathBuf {
+	match path.file_name() {
 		Some(name) => Path::new(name).to_path_buf(),
 		None => {
 			use regex::Regex;
@@ -162,37 +326,135 @@ fn get_name(path: &Path, name_start: &Option<PathBuf>) -> PathBuf {
 			result.push_str(drive);
 			Path::new(&result).to_path_buf()
 		}
-	};
-	match name_start {
-		Some(name_start) => name_start.join(name),
-		None => name,
 	}
 }
 
 #[cfg(unix)]
-fn get_name(path: &Path, name_start: &Option<PathBuf>) -> PathBuf {
-	let name = Path::new(
+fn get_file_name(path: &Path) -> PathBuf {
+	Path::new(
 		path.file_name().unwrap_or_else(|| std::ffi::OsStr::new("root"))
-	).to_path_buf();
-	match name_start {
-		Some(name_start) => name_start.join(name),
-		None => name,
+	).to_path_buf()
+}
+
+fn get_full_name(path: &Path) -> PathBuf {
+	let mut name = PathBuf::new();
+	for component in path.components() {
+		match component {
+			Component::Normal(component) => name.push(component),
+			Component::Prefix(_) => name.push(get_file_name(Path::new(component.as_os_str()))),
+			_ => {}
+		}
+	}
+	if name.as_os_str().is_empty() {
+		get_file_name(path)
+	} else {
+		name
+	}
+}
+
+fn get_name(path: &Path, name_start: &Option<PathBuf>) -> PathBuf {
+	if let Some(name_start) = name_start {
+		return name_start.join(get_file_name(path));
+	}
+	let config = config!();
+	if let Some(alias) = config.path_configs.get(path).and_then(|path_config| path_config.alias.as_ref()) {
+		return alias.clone();
+	}
+	let stripped = config.strip_prefix
+		.as_ref()
+		.and_then(|prefix| path.strip_prefix(prefix).ok())
+		.filter(|name| !name.as_os_str().is_empty());
+	match stripped {
+		Some(name) => name.to_path_buf(),
+		None if config.full_paths => get_full_name(path),
+		None => get_file_name(path),
+	}
+}
+
+fn transform_name(name: &Path) -> PathBuf {
+	let transform = config!(transform);
+	if transform.is_empty() {
+		return name.to_path_buf();
+	}
+	let mut bytes = name.as_os_str().as_encoded_bytes().to_vec();
+	for rule in transform {
+		bytes = rule.pattern.replacen(&bytes, rule.limit, rule.replacement.as_slice()).into_owned();
+	}
+	bytes_to_path(bytes)
+}
+
+fn append_entry<W: Write>(builder: &mut Builder<W>, path: &Path, name: &Path) -> io::Result<()> {
+	let records = if *config!(preserve_xattrs) {
+		pax::xattr_records(path)?
+	} else {
+		Vec::new()
+	};
+	if *config!(tar_format) != TarFormat::Gnu {
+		return pax::append_entry(builder, path, name, records);
+	}
+	let meta = metadata(path)?;
+	let mut header = Header::new_gnu();
+	pax::set_metadata(&mut header, &meta)?;
+	if meta.is_file() {
+		let data = sparse::prepare_file(&mut header, File::open(path)?, &meta)?;
+		pax::append_extensions(builder, &records)?;
+		builder.append_data(&mut header, name, data)
+	} else if meta.is_symlink() {
+		let target = fs::read_link(path)?;
+		pax::append_extensions(builder, &records)?;
+		builder.append_link(&mut header, name, target)
+	} else {
+		pax::set_device(&mut header, &meta)?;
+		pax::append_extensions(builder, &records)?;
+		builder.append_data(&mut header, name, io::empty())
 	}
 }
 
-fn archive_internal<'a, W: Write + Send + 'static>(
+fn append_path<W: EntryWriter>(
 	builder: &mut Builder<W>,
-	output_file_id: FileID,
+	archived_files: &mut HashMap<FileID, PathBuf>,
+	path: &Path,
+	name: &Path,
+	file_id: Option<FileID>,
+) -> io::Result<()> {
+	let name = &transform_name(name);
+	if !builder.get_mut().start_entry(store::is_stored(path)) {
+		store::set_aside(path, name);
+		return Ok(());
+	}
+	let Some(file_id) = file_id else {
+		return append_entry(builder, path, name);
+	};
+	if let Some(target) = archived_files.get(&file_id) {
+		if *config!(tar_format) != TarFormat::Gnu {
+			return pax::append_link(builder, path, name, target);
+		}
+		let mut header = Header::new_gnu();
+		pax::set_metadata(&mut header, &metadata(path)?)?;
+		header.set_entry_type(EntryType::Link);
+		header.set_size(0);
+		return builder.append_link(&mut header, name, target);
+	}
+	append_entry(builder, path, name)?;
+	archived_files.insert(file_id, name.to_path_buf());
+	Ok(())
+}
+
+fn archive_internal<'a, W: EntryWriter + Send + 'static>(
+	builder: &mut Builder<W>,
+	excluded_files: &[FileID],
 	paths: impl Iterator<Item = impl AsRef<Path>>,
 	name_start: &Option<PathBuf>,
 	failed_access: fn(&Path, &io::Error) -> bool,
 ) {
-	'main: for path_ref in paths {
-		let path_ref = path_ref.as_ref();
-		let path = try_access!(path_ref, path_ref.canonicalize(), continue 'main, failed_access);
+	let mut archived_files = HashMap::new();
+	for path_ref in paths {
+		//the paths to backup were already resolved when loading the configuration
+		let path = path_ref.as_ref().to_path_buf();
+		config!().enter_root(&path);
 		let name = get_name(&path, name_start);
 		if *config!(progress_bars) {
-			scan_path(output_file_id, path, name, failed_access, &mut |path, name| {
+			scan_path(excluded_files, path, name, &mut Vec::new(), failed_access, &mut |path, name, file_id| {
 				unsafe {
 					BarsHandler::exec(|bars_handler| {
 						bars_handler.tar_bar.inc(1);
@@ -202,125 +464,500 @@ fn archive_internal<'a, W: Write + Send + 'static>(
 						));
 					});
 				}
-				builder.append_path_with_name(path, name)
+				append_path(builder, &mut archived_files, path, name, file_id)
 			})
 		} else {
-			scan_path(output_file_id, path, name, failed_access, &mut |path, name| {
+			scan_path(excluded_files, path, name, &mut Vec::new(), failed_access, &mut |path, name, file_id| {
 				println!(
 					"Archiving `{}`",
 					path.display().to_string().cyan().bold()
 				);
-				builder.append_path_with_name(path, name)
+				append_path(builder, &mut archived_files, path, name, file_id)
 			})
 		};
 	}
 }
 
-fn archive<'a, W: Write + Send + 'static>(
+fn archive<'a, W: EntryWriter + Send + 'static>(
 	writer: W,
-	output_file_id: FileID,
+	excluded_files: &[FileID],
 	paths: impl Iterator<Item = impl AsRef<Path>>,
+	name_start: &Option<PathBuf>,
 	failed_access: fn(&Path, &io::Error) -> bool,
 ) -> Builder<W> {
 	let mut builder = Builder::new(writer);
 	builder.follow_symlinks(*config!(follow_symlinks));
-	archive_internal(&mut builder, output_file_id, paths, &None, failed_access);
-	builder.finish().unwrap_or_exit();
+	archive_internal(&mut builder, excluded_files, paths, name_start, failed_access);
+	//a cancelled archive is thrown away, so it's left unfinished
+	if !is_cancelled() {
+		builder.finish().unwrap_or_exit();
+	}
 	builder
 }
 
-fn make_subarchives<W: Write + Send + 'static>(
-	mut builder: Builder<W>,
-	output_file_id: FileID,
-	main_thread: &thread::Thread,
-	paths: &Vec<PathBuf>,
-	name_start: Option<PathBuf>,
-	failed_access: fn(&Path, &io::Error) -> bool,
-) {
-	macro_rules! try_access {
-		($path:expr, $f:expr) => {
-			self::try_access!($path, $f, return, failed_access)
-		};
+/// A subarchive to create, named after `dir_path` unless it's given a different name.
+struct Subarchive {
+	dir_path: PathBuf,
+	name: PathBuf,
+	contents: Vec<PathBuf>,
+	root: Option<&'static PathConfig>,
+	filters: &'static [Filter],
+}
+
+/// The size of some files, together with how much of it belongs to each extension with its own filters.
+#[derive(Default)]
+struct DirSize {
+	size: u64,
+	extension_sizes: HashMap<OsString, u64>,
+}
+
+impl DirSize {
+	fn add_file(&mut self, path: &Path, size: u64) {
+		self.size += size;
+		let extension = path.extension().map(OsStr::to_ascii_lowercase);
+		if let Some(extension) = extension.filter(|extension| config!().extension_filters.contains_key(extension)) {
+			*self.extension_sizes.entry(extension).or_default() += size;
+		}
 	}
 
-	let mut root_files = Vec::new();
-	let mut root_dirs = Vec::with_capacity(paths.len());
-	for path_ref in paths {
-		if is_excluded(path_ref.as_os_str().as_encoded_bytes()) {
+	fn add(&mut self, other: &DirSize) {
+		self.size += other.size;
+		for (extension, size) in &other.extension_sizes {
+			*self.extension_sizes.entry(extension.clone()).or_default() += size;
+		}
+	}
+
+	//the filter chain of the extension making up most of the files, if it has one
+	fn filters(&self) -> &'static [Filter] {
+		let config = config!();
+		self.extension_sizes
+			.iter()
+			.find(|(_, size)| *size * 2 > self.size)
+			.map_or(&config.filters, |(extension, _)| &config.extension_filters[extension])
+	}
+}
+
+//the size of the files inside of every directory that `scan_path` enters, measured in a single scan,
+//it's used to decide whether to merge or split them and which filters to use, nested paths to backup
+//are only measured on their own
+fn dir_sizes(paths: &[PathBuf]) -> HashMap<PathBuf, DirSize> {
+	//a directory is closed once the scan leaves it, adding its size to the one of its parent
+	fn close_dir(open_dirs: &mut Vec<(PathBuf, DirSize)>, sizes: &mut HashMap<PathBuf, DirSize>) {
+		let (dir_path, size) = open_dirs.pop().unwrap();
+		if let Some((_, parent_size)) = open_dirs.last_mut() {
+			parent_size.add(&size);
+		}
+		sizes.insert(dir_path, size);
+	}
+
+	let mut sizes = HashMap::new();
+	let mut open_dirs = Vec::new();
+	for path in paths {
+		config!().enter_root(path);
+		scan_path(&[], path.clone(), PathBuf::new(), &mut Vec::new(), |_, _| true, &mut |path, _, _| {
+			while open_dirs.last().is_some_and(|(dir_path, _)| !path.starts_with(dir_path)) {
+				close_dir(&mut open_dirs, &mut sizes);
+			}
+			if let Ok(meta) = metadata(path) {
+				if meta.is_dir() {
+					open_dirs.push((path.clone(), DirSize::default()));
+				} else if let Some((_, size)) = open_dirs.last_mut().filter(|_| meta.is_file()) {
+					size.add_file(path, meta.len());
+				}
+			}
+			Ok(())
+		});
+		while !open_dirs.is_empty() {
+			close_dir(&mut open_dirs, &mut sizes);
+		}
+	}
+	sizes
+}
+
+//the paths of the contents of a directory, without the paths to backup nested inside of it that are
+//archived on their own, like `scan_path` does
+fn content_paths(contents: Vec<DirEntry>) -> Vec<PathBuf> {
+	let config = config!();
+	contents
+		.into_iter()
+		.map(|entry| entry.path())
+		.filter(|path| !config.nested_paths.contains(path))
+		.collect()
+}
+
+//decides which directories become subarchives, directories are entered until `backup.subarchive_depth`
+//is reached (while they're the only one if it's 0) or if they're bigger than `backup.subarchive_max_size`
+//the files inside of an entered directory are put in a subarchive named after it, together with the
+//directories smaller than `backup.subarchive_min_size`, the files outside of any directory are returned
+fn plan_subarchives(
+	subarchives: &mut Vec<Subarchive>,
+	paths: Vec<PathBuf>,
+	parent: Option<(PathBuf, PathBuf)>,
+	depth: u32,
+	sizes: &HashMap<PathBuf, DirSize>,
+	failed_access: fn(&Path, &io::Error) -> bool,
+) -> Vec<PathBuf> {
+	let config = config!();
+	let mut files = Vec::new();
+	let mut dirs = Vec::with_capacity(paths.len());
+	for path in paths {
+		config.enter_root(&path);
+		if is_excluded(path.as_os_str().as_encoded_bytes()) {
 			continue
 		}
-		if path_ref.is_dir() {
-			root_dirs.push(path_ref);
+		//symlinks to directories are only entered when following symlinks, like `scan_path` does
+		if metadata(&path).is_ok_and(|meta| meta.is_dir()) {
+			dirs.push(path);
 		} else {
-			root_files.push(path_ref);
+			files.push(path);
+		}
+	}
+	let name_start = parent.as_ref().map(|(_, name)| name.clone());
+	let only_dir = dirs.len() == 1;
+	for dir_path in dirs {
+		config.enter_root(&dir_path);
+		//mount points that shouldn't be entered are archived as they are by `scan_path`
+		if !can_enter(&dir_path) {
+			files.push(dir_path);
+			continue;
+		}
+		let size = sizes.get(&dir_path).map_or(0, |size| size.size);
+		let enter = match config.subarchive_depth {
+			0 => only_dir,
+			max_depth => depth < max_depth,
+		} || config.subarchive_max_size != 0 && size > config.subarchive_max_size;
+		if enter {
+			//a symlink to one of the parents would be entered forever
+			if let Some((parent_path, _)) = parent.as_ref().filter(|_| config.follow_symlinks()) {
+				let path = try_access!(dir_path, dir_path.canonicalize(), continue, failed_access);
+				if parent_path.canonicalize().is_ok_and(|parent_path| parent_path.starts_with(&path)) {
+					failed_access(&dir_path, &io::Error::other(FilesystemLoop));
+					continue;
+				}
+			}
+			let Some((contents, ..)) = get_dir_contents(&dir_path, &failed_access) else {
+				continue;
+			};
+			let name = get_name(&dir_path, &name_start);
+			files.extend(plan_subarchives(
+				subarchives,
+				content_paths(contents),
+				Some((dir_path, name)),
+				depth + 1,
+				sizes,
+				failed_access
+			));
+			continue;
+		}
+		if parent.is_some() && size < config.subarchive_min_size {
+			files.push(dir_path);
+			continue;
 		}
+		let Some((contents, ..)) = get_dir_contents(&dir_path, &failed_access) else {
+			continue;
+		};
+		let filters = sizes.get(&dir_path).map_or(&config.filters[..], DirSize::filters);
+		subarchives.push(Subarchive {
+			name: get_name(&dir_path, &name_start),
+			dir_path,
+			contents: content_paths(contents),
+			root: config.root(),
+			filters,
+		});
 	}
-	println!("{root_files:?}\n{root_dirs:?}");
-	archive_internal(&mut builder, output_file_id, root_files.into_iter(), &name_start, failed_access);
-	if root_dirs.len() == 1 {
-		let path = try_access!(&paths[0], paths[0].canonicalize());
-		let mut inner_paths = Vec::new();
-		let name_start = Some(get_name(path.as_path(), &name_start));
-		for entry in try_access!(path, path.read_dir()) {
-			inner_paths.push(try_access!(path, entry).path());
+	match parent {
+		Some((dir_path, name)) => {
+			if !files.is_empty() {
+				//the files are measured on their own, the directories were already measured by `dir_sizes`
+				let mut size = DirSize::default();
+				for path in &files {
+					if let Some(dir_size) = sizes.get(path) {
+						size.add(dir_size);
+					} else if let Some(meta) = metadata(path).ok().filter(|meta| meta.is_file()) {
+						size.add_file(path, meta.len());
+					}
+				}
+				subarchives.push(Subarchive {
+					dir_path,
+					name,
+					contents: files,
+					root: config.root(),
+					filters: size.filters(),
+				});
+			}
+			Vec::new()
 		}
-		make_subarchives(
-			builder,
-			output_file_id,
-			main_thread,
-			&inner_paths,
-			name_start,
+		//there is no directory to name a subarchive after
+		None => files,
+	}
+}
+
+//appends the header of a subarchive followed by its contents, the header is returned so that it
+//can be fixed afterwards if `size` wasn't known in advance
+fn append_subarchive<W: Write>(
+	builder: &mut Builder<W>,
+	subarchive: &Subarchive,
+	size: u64,
+	data: &mut dyn Read,
+) -> io::Result<Header> {
+	let tar_format = *config!(tar_format);
+	let mut header = if tar_format == TarFormat::Gnu {
+		Header::new_gnu()
+	} else {
+		Header::new_ustar()
+	};
+	pax::set_metadata(&mut header, &subarchive.dir_path.metadata()?)?;
+	header.set_mode(header.mode().unwrap() ^ 0o140000);
+	header.set_entry_type(EntryType::Regular);
+	header.set_size(size);
+	let path_name = transform_name(&subarchive.name.with_extension("tar.xz"));
+	if tar_format == TarFormat::Gnu {
+		builder.append_data(&mut header, path_name, data)?;
+	} else {
+		let mut records = Vec::new();
+		pax::set_path(&mut header, &mut records, &path_name)?;
+		header.set_cksum();
+		pax::append_extensions(builder, &records)?;
+		builder.append(&header, data)?;
+	}
+	Ok(header)
+}
+
+//stored files are put next to the subarchive, inside of a directory with the same name
+fn append_set_aside<W: EntryWriter>(
+	builder: &mut Builder<W>,
+	subarchive: &Subarchive,
+	set_aside: Vec<(PathBuf, PathBuf)>,
+	failed_access: fn(&Path, &io::Error) -> bool,
+) {
+	let stored_name_start = transform_name(&subarchive.name);
+	for (path, name) in set_aside {
+		self::try_access!(
+			path,
+			append_entry(builder, &path, &stored_name_start.join(&name)),
+			continue,
+			failed_access
+		);
+	}
+}
+
+//archives the contents of a subarchive from another thread, which returns the entries that were
+//set aside while archiving them, it stops early once `cancelled` is set
+fn spawn_archiver(
+	writer: PipeWriter,
+	subarchive: &Subarchive,
+	excluded_files: Arc<[FileID]>,
+	cancelled: Arc<AtomicBool>,
+	failed_access: fn(&Path, &io::Error) -> bool,
+) -> JoinHandle<Vec<(PathBuf, PathBuf)>> {
+	let contents = subarchive.contents.clone();
+	let root = subarchive.root;
+	thread::spawn(move || {
+		config!().set_root(root);
+		stop_when(cancelled);
+		//the entries are named relative to the subarchive, never by their full path or alias
+		archive(
+			StoreWriter::new(writer, StoreMode::SetAside),
+			&excluded_files,
+			contents.iter(),
+			&Some(PathBuf::new()),
 			failed_access
 		);
+		store::take_set_aside()
+	})
+}
+
+//writes again the header of the last subarchive appended, now that its size is known
+fn fix_subarchive_header(output_file: &mut File, header: &mut Header, size: u64) -> io::Result<()> {
+	header.set_size(size);
+	header.set_cksum();
+	let header_pos = output_file.stream_position()? - size.next_multiple_of(512) - 512;
+	output_file.seek(SeekFrom::Start(header_pos))?;
+	output_file.write_all(header.as_bytes())?;
+	output_file.seek(SeekFrom::End(0))?;
+	Ok(())
+}
+
+//a path that is removed once dropped
+struct TempPath(PathBuf);
+
+impl Drop for TempPath {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.0);
+	}
+}
+
+//a temporary file next to the backup, named after the process so that runs can't collide
+struct SpillFile {
+	file: File,
+	//fields are dropped in order, so the file is closed before being removed
+	_path: TempPath,
+}
+
+impl SpillFile {
+	fn create(i: u32) -> io::Result<Self> {
+		let path = PathBuf::from(format!("{}.{}.{i}.part", config!(name), process::id()));
+		let file = File::options()
+			.read(true)
+			.write(true)
+			.create_new(true)
+			.open(&path)?;
+		Ok(Self { file, _path: TempPath(path) })
+	}
+
+	//empties the file so that it can hold another subarchive
+	fn clear(&mut self) -> io::Result<()> {
+		self.file.set_len(0)?;
+		self.file.rewind()
+	}
+}
+
+//a compressed subarchive, its size and the entries that were set aside while archiving it
+type SpilledSubarchive = (SpillFile, u64, Vec<(PathBuf, PathBuf)>);
+
+//archives and compresses a subarchive into a spill file, the archiver is stopped if compressing fails
+fn spill_subarchive(
+	subarchive: &Subarchive,
+	mut spill_file: SpillFile,
+	excluded_files: Arc<[FileID]>,
+	threads: u32,
+	cancelled: Arc<AtomicBool>,
+	failed_access: fn(&Path, &io::Error) -> bool,
+) -> io::Result<SpilledSubarchive> {
+	let (reader, writer) = os_pipe::pipe()?;
+	let archiver = spawn_archiver(writer, subarchive, excluded_files, cancelled.clone(), failed_access);
+	let result = super::stream_builder(
+		*config!(level),
+		subarchive.filters,
+		threads
+	).and_then(|builder| super::compress(&reader, builder, &mut spill_file.file));
+	if result.is_err() {
+		//cancelled before the reader is dropped, so that the archiver doesn't report the broken pipe
+		cancelled.store(true, Ordering::Relaxed);
+	}
+	drop(reader);
+	let set_aside = archiver.join().unwrap();
+	let size = result?;
+	spill_file.file.rewind()?;
+	Ok((spill_file, size, set_aside))
+}
+
+type SubarchiveTask = (Arc<Subarchive>, Arc<AtomicBool>, JoinHandle<io::Result<SpilledSubarchive>>);
+
+//compresses up to `jobs` subarchives at the same time, adding them to the archive in order as soon as
+//all of the previous ones were added, if one fails the others are cancelled and their files removed
+fn make_subarchives_parallel(
+	builder: &mut Builder<File>,
+	output_file_id: FileID,
+	subarchives: Vec<Subarchive>,
+	xz_threads: u32,
+	jobs: u32,
+	failed_access: fn(&Path, &io::Error) -> bool,
+) -> io::Result<()> {
+	let threads = (xz_threads / jobs).max(1);
+	//every job gets a spill file before any starts, so that none of them can be archived by another job
+	let mut spill_files = (0..jobs).map(SpillFile::create).collect::<io::Result<Vec<_>>>()?;
+	let excluded_files = spill_files
+		.iter()
+		.map(|spill_file| spill_file.file.get_id())
+		.chain([Ok(output_file_id)])
+		.collect::<io::Result<Arc<[FileID]>>>()?;
+	let mut running = VecDeque::with_capacity(jobs as usize);
+	let mut finish_task = |(subarchive, _, task): SubarchiveTask| {
+		let (mut spill_file, size, set_aside) = task.join().unwrap()?;
+		append_subarchive(builder, &subarchive, size, &mut spill_file.file)?;
+		spill_file.clear()?;
+		append_set_aside(builder, &subarchive, set_aside, failed_access);
+		io::Result::Ok(spill_file)
+	};
+	let mut result = Ok(());
+	for subarchive in subarchives {
+		if running.len() == jobs as usize {
+			match finish_task(running.pop_front().unwrap()) {
+				Ok(spill_file) => spill_files.push(spill_file),
+				Err(e) => {
+					result = Err(e);
+					break;
+				}
+			}
+		}
+		let subarchive = Arc::new(subarchive);
+		let cancelled = Arc::new(AtomicBool::new(false));
+		let task = thread::spawn({
+			let subarchive = subarchive.clone();
+			let spill_file = spill_files.pop().unwrap();
+			let excluded_files = excluded_files.clone();
+			let cancelled = cancelled.clone();
+			move || spill_subarchive(&subarchive, spill_file, excluded_files, threads, cancelled, failed_access)
+		});
+		running.push_back((subarchive, cancelled, task));
+	}
+	while let Some(task) = running.pop_front() {
+		if result.is_ok() {
+			result = finish_task(task).map(drop);
+			continue;
+		}
+		//the spill file of a cancelled subarchive is removed as soon as its result is dropped
+		task.1.store(true, Ordering::Relaxed);
+		let _ = task.2.join();
+	}
+	result
+}
+
+fn make_subarchives(
+	mut builder: Builder<File>,
+	output_file_id: FileID,
+	job_sender: &Sender<SubarchiveJob>,
+	xz_threads: u32,
+	jobs: u32,
+	failed_access: fn(&Path, &io::Error) -> bool,
+) {
+	let mut subarchives = Vec::new();
+	let config = config!();
+	let measure = config.subarchive_min_size != 0 || config.subarchive_max_size != 0 || !config.extension_filters.is_empty();
+	let sizes = if measure {
+		dir_sizes(&config.paths)
+	} else {
+		HashMap::new()
+	};
+	let root_files = plan_subarchives(&mut subarchives, config.paths.clone(), None, 1, &sizes, failed_access);
+	archive_internal(&mut builder, &[output_file_id], root_files.iter(), &None, failed_access);
+	if jobs != 1 {
+		make_subarchives_parallel(&mut builder, output_file_id, subarchives, xz_threads, jobs, failed_access)
+			.unwrap_or_exit();
 	} else {
-		for dir_path in root_dirs {
-			let Some((contents, ..)) = get_dir_contents(dir_path, &failed_access) else {
+		let excluded_files: Arc<[FileID]> = Arc::from([output_file_id]);
+		for subarchive in subarchives {
+			if is_cancelled() {
 				return;
-			};
+			}
 			let (reader, writer) = os_pipe::pipe().unwrap_or_exit();
-			let subarchive_values = SubarchiveValues {
+			let (chunk_writer, mut chunk_reader) = chunks::channel();
+			let archiver = spawn_archiver(writer, &subarchive, excluded_files.clone(), cancellation(), failed_access);
+			let job = SubarchiveJob {
 				reader,
-				dir_path,
-				name_start: &name_start,
-				builder: &mut builder as *mut _ as usize as *mut Builder<File>,
-				f: |mut compressor, dir_path, name_start, builder| {
-					let mut header = Header::new_gnu();
-					header.set_metadata(&dir_path.metadata()?);
-					header.set_mode(header.mode().unwrap() ^ 0o140000);
-					header.set_entry_type(EntryType::Regular);
-					let path_name = get_name(&dir_path, &name_start);
-					let header_pos = builder.get_mut().stream_position()?;
-					builder.append_data(
-						&mut header,
-						path_name.with_extension("tar.xz"),
-						&mut compressor,
-					)?;
-					header.set_size(compressor.borrow_compressor().total_out());
-					header.set_cksum();
-					let output_file = builder.get_mut();
-					output_file.seek(SeekFrom::Start(header_pos))?;
-					output_file.write(header.as_bytes())?;
-					output_file.seek(SeekFrom::End(0))?;
-					Ok(())
-				}
+				filters: subarchive.filters,
+				writer: chunk_writer,
 			};
-			// SAFETY: Recieving thread is parked.
-			unsafe { SUBARCHIVE_VALUES.set(&subarchive_values) }
-			main_thread.unpark();
-			archive(writer, output_file_id, contents.into_iter().map(|entry| entry.path()), failed_access);
-			thread::park();
+			if job_sender.send(job).is_err() {
+				//the compressor stopped, it will report why
+				return;
+			}
+			let mut header = append_subarchive(&mut builder, &subarchive, 0, &mut chunk_reader).unwrap_or_exit();
+			fix_subarchive_header(builder.get_mut(), &mut header, chunk_reader.received()).unwrap_or_exit();
+			append_set_aside(&mut builder, &subarchive, archiver.join().unwrap(), failed_access);
 		}
-		builder.finish().unwrap_or_exit();
 	}
+	builder.finish().unwrap_or_exit();
 }
 
-pub fn spawn_thread<W: Write + Send + 'static>(
-	writer: W,
-	output_file_id: FileID
+fn spawn_tar_thread(
+	cancelled: Arc<AtomicBool>,
+	f: impl FnOnce(fn(&Path, &io::Error) -> bool) + Send + 'static,
 ) -> JoinHandle<()> {
 	let config = config!();
-	let main_thread = config.use_multiple_subarchives.then(thread::current);
 	thread::spawn(move || {
+		stop_when(cancelled);
 		let failed_access: Box<fn(&Path, &io::Error) -> bool> = if config.progress_bars {
 			Box::new(|path, e| {
 				unsafe {
@@ -332,15 +969,9 @@ pub fn spawn_thread<W: Write + Send + 'static>(
 				}
 			})
 		} else { Box::new(failed_access) };
-		if let Some(main_thread) = main_thread {
-			let mut builder = Builder::new(writer);
-			builder.follow_symlinks(*config!(follow_symlinks));
-			make_subarchives(builder, output_file_id, &main_thread, &config.paths, None, *failed_access);
-			// SAFETY: Recieving thread is parked.
-			unsafe { SUBARCHIVE_VALUES.set(ptr::null()) }
-			main_thread.unpark();
-		} else {
-			archive(writer, output_file_id, config.paths.iter(), *failed_access);
+		f(*failed_access);
+		if is_cancelled() {
+			return;
 		}
 		if config.progress_bars {
 			unsafe {
@@ -424,3 +1055,31 @@ pub fn spawn_thread<W: Write + Send + 'static>(
 		builder.finish().unwrap_or_exit();*/
 	})
 }
+
+/// Spawns the thread that archives every path to `writer`, it stops early once `cancelled` is set.
+pub fn spawn_thread<W: EntryWriter + Send + 'static>(
+	writer: W,
+	output_file_id: FileID,
+	cancelled: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+	spawn_tar_thread(cancelled, move |failed_access| {
+		archive(writer, &[output_file_id], config!().paths.iter(), &None, failed_access);
+	})
+}
+
+/// Spawns the thread that archives every subarchive, sending them to `job_sender` to be compressed
+/// unless `jobs` subarchives are compressed at the same time, it stops early once `cancelled` is set.
+pub fn spawn_subarchives_thread(
+	output_file: File,
+	output_file_id: FileID,
+	job_sender: Sender<SubarchiveJob>,
+	xz_threads: u32,
+	jobs: u32,
+	cancelled: Arc<AtomicBool>,
+) -> JoinHandle<()> {
+	spawn_tar_thread(cancelled, move |failed_access| {
+		let mut builder = Builder::new(output_file);
+		builder.follow_symlinks(*config!(follow_symlinks));
+		make_subarchives(builder, output_file_id, &job_sender, xz_threads, jobs, failed_access);
+	})
+}
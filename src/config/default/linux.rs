@@ -3,6 +3,30 @@ use toml::{toml, Table};
 pub fn get() -> Table {
 	toml! {
 		[backup]
-		paths = ["/etc", "/home", "/root", "/var"]
+		paths = ["/"]
+		exclude_fs_types = [
+			"proc",
+			"sysfs",
+			"devtmpfs",
+			"devpts",
+			"tmpfs",
+			"cgroup",
+			"cgroup2",
+			"securityfs",
+			"debugfs",
+			"tracefs",
+			"pstore",
+			"bpf",
+			"mqueue",
+			"hugetlbfs",
+			"configfs",
+			"autofs",
+			"binfmt_misc",
+			"nfs",
+			"nfs4",
+			"cifs",
+			"smb3",
+			"fuse.*",
+		]
 	}
 }
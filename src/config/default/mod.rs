@@ -33,12 +33,34 @@ pub fn get() -> Table {
 	let mut config = toml! {
 		[backup]
 		paths = []
+		overlapping_paths = "drop-nested"
 		exclude = ["?/cache/i"]
+		exclude_from = []
 		exclude_tags = { "CACHEDIR.TAG" = "keep-tag" }
+		exclude_fs_types = []
+		one_file_system = false
+		allowed_file_systems = []
 		follow_symlinks = false
+		preserve_xattrs = false
+		sparse = true
 		ignore_unreadable_files = false
 		force_overwrite = false
 		use_multiple_subarchives = false
+		subarchive_jobs = 1
+		subarchive_depth = 0
+		subarchive_min_size = 0
+		subarchive_max_size = 0
+		tar_format = "gnu"
+		sort_order = "natural"
+		store_extensions = []
+		detect_compressed = false
+		full_paths = false
+		strip_prefix = ""
+		transform = []
+		owner_map = {}
+		group_map = {}
+		numeric_owner = true
+		reproducible = false
 		name = "%!hostname (%F).tar.xz"
 
 		[progress_bars]
@@ -52,6 +74,17 @@ pub fn get() -> Table {
 		level = 8
 		threads = threads
 		block_size = 0
+		extreme = false
+		dict_size = 0
+		lc = 3
+		lp = 0
+		pb = 2
+		match_finder = "auto"
+		nice_len = 0
+		filters = ["lzma2"]
+		extension_filters = {}
+		check = "crc64"
+		memory_limit = 0
 	};
 	let specifics = specifics::get();
 	for table_key in specifics.keys() {
@@ -1,16 +1,17 @@
 use std::{
-	collections::HashMap,
+	cell::Cell,
+	collections::{HashMap, HashSet},
 	error::Error,
 	ffi::OsString,
 	fmt::Debug,
 	hint::unreachable_unchecked,
-	path::PathBuf,
+	path::{Component, Path, PathBuf},
 	process,
 	str::FromStr,
-	sync::Mutex,
+	sync::{atomic::Ordering, Mutex, OnceLock, RwLock},
 	env,
 	fs,
-	io,
+	io::{self, Read},
 };
 use chrono::{Local, format::{DelayedFormat, Item, StrftimeItems}};
 use clap::{
@@ -20,6 +21,7 @@ use clap::{
 	crate_name,
 	ValueEnum,
 	Parser,
+	Subcommand,
 };
 use colored::Colorize;
 use dirs::config_dir;
@@ -29,7 +31,6 @@ use toml::{value::Array, Table, Value};
 use crate::{
 	backup::bars::{spinner_chars, PROGRESS_BAR},
 	error::{self, ResultExt},
-	static_ptr::StaticPointer,
 	input,
 };
 
@@ -49,10 +50,7 @@ macro_rules! map {
 
 macro_rules! config {
 	() => {
-		//SAFETY: The configuration will always be initialized by the time this macro is used.
-		unsafe {
-			crate::config::CONFIG.deref()
-		}
+		crate::config::get()
 	};
 	($field:ident) => {
 		&config!().$field
@@ -94,6 +92,9 @@ pub(crate) use assert_config;
 	long_about = None
 )]
 struct Cli {
+	#[command(subcommand)]
+	command: Option<Command>,
+
 	/// Path to the configuration file
 	#[arg(
 		short,
@@ -135,6 +136,23 @@ struct Cli {
 	#[arg(short = 'P', long, value_delimiter = ',', value_name = "PATHS")]
 	add_paths: Vec<PathBuf>,
 
+	/// Read the paths to backup from a file (one per line) or from stdin if FILE is `-`,
+	/// every path is archived with the name it has in the list
+	#[arg(short = 'T', long, value_name = "FILE", conflicts_with = "paths")]
+	files_from: Option<PathBuf>,
+
+	/// Paths read with --files-from are separated by null characters rather than newlines
+	#[arg(short = '0', long, requires = "files_from")]
+	null: bool,
+
+	/// Archive the contents of the directories read with --files-from too
+	#[arg(long, requires = "files_from")]
+	recursion: bool,
+
+	/// How to handle paths that are inside of other paths to backup [default: use configuration]
+	#[arg(long, value_enum, ignore_case(true), value_name = "MODE")]
+	overlapping_paths: Option<OverlapMode>,
+
 	/// List of patterns to exclude [default: use configuration]
 	#[arg(short, long, value_delimiter = ',', value_name = "PATTERNS")]
 	exclude: Option<Vec<String>>,
@@ -143,6 +161,10 @@ struct Cli {
 	#[arg(short = 'E', long, value_delimiter = ',', value_name = "PATTERNS")]
 	add_exclude: Vec<String>,
 
+	/// Add more files to read excluded patterns from (one per line)
+	#[arg(long, value_delimiter = ',', value_name = "FILES")]
+	exclude_from: Vec<PathBuf>,
+
 	/// Ignore the excluded tags defined in the configuration file
 	#[arg(long)]
 	allow_tags: bool,
@@ -159,11 +181,31 @@ struct Cli {
 	#[arg(long, value_delimiter = ',', value_name = "TAGS")]
 	exclude_tags_all: Vec<OsString>,
 
-	/// Archive what symlinks link to rather than the symlink itself, may get stuck in a loop
+	/// List of filesystem types whose mount points will be skipped, Linux only [default: use configuration]
+	#[arg(long, value_delimiter = ',', value_name = "TYPES")]
+	exclude_fs_types: Option<Vec<String>>,
+
+	/// Don't enter directories that belong to a different filesystem than the backup paths
 	/// [default: use configuration]
+	#[arg(short = 'x', long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
+	one_file_system: Option<bool>,
+
+	/// Add more paths whose filesystem can be entered even when staying on one filesystem
+	#[arg(long, value_delimiter = ',', value_name = "PATHS")]
+	allow_file_systems: Vec<PathBuf>,
+
+	/// Archive what symlinks link to rather than the symlink itself [default: use configuration]
 	#[arg(short = 's', long, value_name = "FOLLOW", default_missing_value = "true", num_args = 0..=1)]
 	follow_symlinks: Option<bool>,
 
+	/// Store extended attributes (including ACLs and SELinux labels), Unix only [default: use configuration]
+	#[arg(short = 'X', long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
+	preserve_xattrs: Option<bool>,
+
+	/// Omit the empty parts of sparse files from the backup [default: use configuration]
+	#[arg(long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
+	sparse: Option<bool>,
+
 	/// Skip files that failed to be read rather than asking the user [default: use configuration]
 	#[arg(short, long, value_name = "IGNORE", default_missing_value = "true", num_args = 0..=1)]
 	ignore_unreadable_files: Option<bool>,
@@ -176,6 +218,64 @@ struct Cli {
 	#[arg(short = 'm', long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
 	use_multiple_subarchives: Option<bool>,
 
+	/// Amount of subarchives compressed at the same time, 0 to use one per XZ thread [default: use configuration]
+	#[arg(long, value_name = "JOBS")]
+	subarchive_jobs: Option<u32>,
+
+	/// How many directories deep subarchives are made, 0 to split at the first level with more than one directory [default: use configuration]
+	#[arg(long, value_name = "DEPTH")]
+	subarchive_depth: Option<u32>,
+
+	/// Size under which directories are merged into the subarchive of their parent, 0 to never merge [default: use configuration]
+	#[arg(long, value_name = "SIZE")]
+	subarchive_min_size: Option<String>,
+
+	/// Size over which directories are split into multiple subarchives, 0 to never split [default: use configuration]
+	#[arg(long, value_name = "SIZE")]
+	subarchive_max_size: Option<String>,
+
+	/// Format used for the headers of the archived files [default: use configuration]
+	#[arg(long, value_enum, ignore_case(true), value_name = "FORMAT")]
+	tar_format: Option<TarFormat>,
+
+	/// Order in which the contents of each directory are archived [default: use configuration]
+	#[arg(long, value_enum, ignore_case(true), value_name = "ORDER")]
+	sort_order: Option<SortOrder>,
+
+	/// Store files that look already compressed without compressing them again
+	/// [default: use configuration]
+	#[arg(long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
+	detect_compressed: Option<bool>,
+
+	/// Name the paths to backup by their full path rather than by their last component
+	/// [default: use configuration]
+	#[arg(long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
+	full_paths: Option<bool>,
+
+	/// Name the paths to backup inside of this directory by their path relative to it
+	/// [default: use configuration]
+	#[arg(long, value_name = "PATH")]
+	strip_prefix: Option<PathBuf>,
+
+	/// Add a sed-like rule (s/regex/replacement/flags) to the rules applied to the name of every
+	/// archived file, can be used multiple times
+	#[arg(long, value_name = "RULE")]
+	transform: Vec<String>,
+
+	/// Store only the ids of the owners of the archived files, without their names
+	/// [default: use configuration]
+	#[arg(long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
+	numeric_owner: Option<bool>,
+
+	/// Create the same archive every time the same files are archived [default: use configuration]
+	#[arg(short = 'r', long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
+	reproducible: Option<bool>,
+
+	/// Store modification times newer than this Unix timestamp as the timestamp itself
+	/// [default: use configuration]
+	#[arg(long, value_name = "TIMESTAMP")]
+	clamp_mtime: Option<u64>,
+
 	/// Name (or path) of the backup file [default: use configuration]
 	#[arg(short, long)]
 	name: Option<String>,
@@ -204,11 +304,77 @@ struct Cli {
 	#[arg(short, long)]
 	block_size: Option<u64>,
 
+	/// Use the slower extreme variant of the compression level [default: use configuration]
+	#[arg(long, value_name = "ENABLE", default_missing_value = "true", num_args = 0..=1)]
+	extreme: Option<bool>,
+
+	/// Size of the LZMA2 dictionary in bytes, 0 uses the one of the compression level
+	/// [default: use configuration]
+	#[arg(long, value_name = "SIZE")]
+	dict_size: Option<u32>,
+
+	/// Number of literal context bits (0-4) [default: use configuration]
+	#[arg(long)]
+	lc: Option<u32>,
+
+	/// Number of literal position bits (0-4) [default: use configuration]
+	#[arg(long)]
+	lp: Option<u32>,
+
+	/// Number of position bits (0-4) [default: use configuration]
+	#[arg(long)]
+	pb: Option<u32>,
+
+	/// Match finder used by LZMA2 [default: use configuration]
+	#[arg(long, value_enum, ignore_case(true))]
+	match_finder: Option<MatchFinder>,
+
+	/// Length of a match good enough to stop looking for better ones (2-273), 0 uses the one of the
+	/// compression level [default: use configuration]
+	#[arg(long, value_name = "LEN")]
+	nice_len: Option<u32>,
+
+	/// Filter chain used by XZ, ending with lzma2 [default: use configuration]
+	#[arg(long, value_name = "FILTER", value_delimiter = ',')]
+	filters: Vec<String>,
+
+	/// Type of integrity check stored in the compressed file [default: use configuration]
+	#[arg(long, value_enum, ignore_case(true))]
+	check: Option<IntegrityCheck>,
+
+	/// Maximum amount of memory XZ is allowed to use, in bytes (KiB, MiB and GiB can be used) or as a
+	/// percentage of the total memory, 0 means no limit [default: use configuration]
+	#[arg(long, value_name = "LIMIT")]
+	memory_limit: Option<String>,
+
 	/// Update any outdated configuration automatically instead of asking
 	#[arg(short, long)]
 	auto_update_config: bool,
 }
 
+#[derive(Debug, Clone, Subcommand)]
+pub enum Command {
+	/// Estimate the size of the backup and the time and memory needed to create it, by compressing
+	/// a sample of the files to backup at different compression levels
+	Estimate {
+		/// Fraction of the data to backup used as sample (at most 256 MiB are sampled)
+		#[arg(short, long, default_value_t = 0.05, value_name = "FRACTION")]
+		sample: f64,
+
+		/// Compression levels to try [default: 0, 3, 6, 9 and the configured level]
+		#[arg(short, long, value_delimiter = ',', value_name = "LEVELS")]
+		levels: Vec<u32>,
+	},
+
+	/// Measure how fast the files to backup can be read and compressed with different amounts of
+	/// threads and block sizes, and suggest the fastest configuration that fits in memory
+	Bench {
+		/// Amount of data read from the files to backup and compressed, in MiB
+		#[arg(short, long, default_value_t = 256, value_name = "MIB")]
+		size: u64,
+	},
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
 enum ColorMode {
 	Auto,
@@ -244,15 +410,147 @@ pub enum TagKeepMode {
 	None,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OverlapMode {
+	/// Archive the nested path only as part of the path containing it.
+	DropNested,
+
+	/// Archive the nested path on its own, skipping it inside of the path containing it.
+	KeepNested,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TarFormat {
+	/// GNU tar headers, using GNU extensions for long paths and sparse files.
+	Gnu,
+
+	/// POSIX ustar headers, using PAX extended headers for anything that doesn't fit in them.
+	Pax,
+
+	/// POSIX ustar headers only, files that can't be represented will fail to be archived.
+	Ustar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SortOrder {
+	/// The order the file system lists them in (alphabetical for reproducible backups).
+	Natural,
+
+	/// Grouped by extension, so that similar files are compressed together.
+	Extension,
+
+	/// From the smallest to the biggest file.
+	Size,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum IntegrityCheck {
+	/// Don't store any integrity check.
+	None,
+
+	/// 32-bit cyclic redundancy check.
+	Crc32,
+
+	/// 64-bit cyclic redundancy check, used by XZ itself by default.
+	Crc64,
+
+	/// SHA-256 hash, slower but much harder to collide.
+	Sha256,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MatchFinder {
+	/// The match finder of the compression level.
+	Auto,
+
+	/// Hash chain with 2- and 3-byte hashing.
+	Hc3,
+
+	/// Hash chain with 2-, 3- and 4-byte hashing.
+	Hc4,
+
+	/// Binary tree with 2-byte hashing.
+	Bt2,
+
+	/// Binary tree with 2- and 3-byte hashing.
+	Bt3,
+
+	/// Binary tree with 2-, 3- and 4-byte hashing.
+	Bt4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryLimit {
+	Bytes(u64),
+	Percentage(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+	X86,
+	PowerPc,
+	Ia64,
+	Arm,
+	ArmThumb,
+	Arm64,
+	Sparc,
+	RiscV,
+	Delta(u16),
+	Lzma2,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct PathConfig {
+	pub alias: Option<PathBuf>,
+	pub exclude: Option<Vec<bytes::Regex>>,
+	pub exclude_tags: Option<HashMap<OsString, TagKeepMode>>,
+	pub follow_symlinks: Option<bool>,
+}
+
+#[derive(Debug)]
+pub struct Transform {
+	pub pattern: bytes::Regex,
+	pub replacement: Vec<u8>,
+	pub limit: usize,
+}
+
 #[derive(Debug)]
 pub struct Config {
+	pub command: Option<Command>,
+	pub config_path: Option<PathBuf>,
+	pub auto_update_config: bool,
 	pub paths: Vec<PathBuf>,
+	pub recursion: bool,
+	pub nested_paths: HashSet<PathBuf>,
 	pub exclude: Vec<bytes::Regex>,
 	pub exclude_tags: HashMap<OsString, TagKeepMode>,
+	pub excluded_mount_points: HashSet<PathBuf>,
+	pub one_file_system: bool,
+	pub allowed_file_systems: Vec<PathBuf>,
+	pub allowed_storage_ids: OnceLock<HashSet<u64>>,
 	pub follow_symlinks: bool,
+	pub preserve_xattrs: bool,
+	pub sparse: bool,
 	pub ignore_unreadable_files: Mutex<bool>,
 	pub force_overwrite: bool,
 	pub use_multiple_subarchives: bool,
+	pub subarchive_jobs: u32,
+	pub subarchive_depth: u32,
+	pub subarchive_min_size: u64,
+	pub subarchive_max_size: u64,
+	pub tar_format: TarFormat,
+	pub sort_order: SortOrder,
+	pub store_extensions: HashSet<OsString>,
+	pub detect_compressed: bool,
+	pub path_configs: HashMap<PathBuf, PathConfig>,
+	pub full_paths: bool,
+	pub strip_prefix: Option<PathBuf>,
+	pub transform: Vec<Transform>,
+	pub owner_map: HashMap<u64, u64>,
+	pub group_map: HashMap<u64, u64>,
+	pub numeric_owner: bool,
+	pub reproducible: bool,
+	pub clamp_mtime: Option<u64>,
 	pub name: String,
 	pub progress_bars: bool,
 	pub spinner_chars: String,
@@ -262,9 +560,63 @@ pub struct Config {
 	pub level: u32,
 	pub threads: u32,
 	pub block_size: u64,
+	pub extreme: bool,
+	pub dict_size: u32,
+	pub lc: u32,
+	pub lp: u32,
+	pub pb: u32,
+	pub match_finder: MatchFinder,
+	pub nice_len: u32,
+	pub filters: Vec<Filter>,
+	pub extension_filters: HashMap<OsString, Vec<Filter>>,
+	pub check: IntegrityCheck,
+	pub memory_limit: MemoryLimit,
 }
 
-pub static mut CONFIG: StaticPointer<Config> = StaticPointer::null();
+static CONFIG: RwLock<Option<&'static Config>> = RwLock::new(None);
+
+/// The configuration loaded by the last call to `init`.
+pub fn get() -> &'static Config {
+	CONFIG.read().unwrap().expect("the configuration is used before being loaded")
+}
+
+thread_local! {
+	static ROOT_CONFIG: Cell<Option<&'static PathConfig>> = const { Cell::new(None) };
+}
+
+impl Config {
+	/// Makes the options of `path` override the global ones for the current thread, if `path` is
+	/// one of the paths to backup.
+	///
+	/// Every path to backup has options, the default ones being empty, so entering a path without
+	/// any goes back to the global options.
+	pub fn enter_root(&'static self, path: &Path) {
+		if let Some(path_config) = self.path_configs.get(path) {
+			ROOT_CONFIG.set(Some(path_config));
+		}
+	}
+
+	/// The path options used by the current thread, which other threads can use with `set_root`.
+	pub fn root(&'static self) -> Option<&'static PathConfig> {
+		ROOT_CONFIG.get()
+	}
+
+	pub fn set_root(&'static self, root: Option<&'static PathConfig>) {
+		ROOT_CONFIG.set(root);
+	}
+
+	pub fn exclude(&'static self) -> &'static [bytes::Regex] {
+		ROOT_CONFIG.get().and_then(|root| root.exclude.as_deref()).unwrap_or(&self.exclude)
+	}
+
+	pub fn exclude_tags(&'static self) -> &'static HashMap<OsString, TagKeepMode> {
+		ROOT_CONFIG.get().and_then(|root| root.exclude_tags.as_ref()).unwrap_or(&self.exclude_tags)
+	}
+
+	pub fn follow_symlinks(&self) -> bool {
+		ROOT_CONFIG.get().and_then(|root| root.follow_symlinks).unwrap_or(self.follow_symlinks)
+	}
+}
 
 fn parse_excluded_tag((name, mode): (&String, &Value)) -> Result<(OsString, TagKeepMode), String> {
 	Ok((OsString::from(name), map!(
@@ -279,6 +631,431 @@ fn parse_excluded_tag((name, mode): (&String, &Value)) -> Result<(OsString, TagK
 	)))
 }
 
+fn expand_path(path: &str, warnings: &mut Vec<String>) -> Result<Vec<PathBuf>, String> {
+	let expanded = match path.strip_prefix('~') {
+		Some(rest) if rest.is_empty() || rest.starts_with(['/', '\\']) => {
+			let home = dirs::home_dir().ok_or("could not find the home directory")?;
+			home.to_string_lossy().into_owned() + rest
+		}
+		_ => path.to_string(),
+	};
+	let mut missing_var = None;
+	let expanded = Regex::new(r"\$(?:\{([A-Za-z_][A-Za-z0-9_]*)\}|([A-Za-z_][A-Za-z0-9_]*))")
+		.unwrap()
+		.replace_all(&expanded, |caps: &Captures| {
+			let name = caps.get(1).or_else(|| caps.get(2)).unwrap().as_str();
+			env::var(name).unwrap_or_else(|_| {
+				missing_var.get_or_insert_with(|| name.to_string());
+				String::new()
+			})
+		})
+		.into_owned();
+	if let Some(name) = missing_var {
+		return Err(format!(
+			"environment variable `{}` used in path `{}` is not set",
+			name.yellow().bold(),
+			path.cyan().bold(),
+		));
+	}
+	if !expanded.contains(['*', '?', '[']) {
+		return Ok(vec![PathBuf::from(expanded)]);
+	}
+	let paths: Vec<PathBuf> = glob::glob(&expanded)
+		.map_err(|e| format!("invalid pattern `{}`\n{e}", path.yellow().bold()))?
+		.filter_map(Result::ok)
+		.collect();
+	if paths.is_empty() {
+		warnings.push(format!(
+			"{} `{}` did not match any path",
+			"warning:".yellow().bold(),
+			path.cyan().bold(),
+		));
+	}
+	Ok(paths)
+}
+
+fn parse_paths(values: &Array, warnings: &mut Vec<String>) -> Result<Vec<(PathBuf, PathConfig)>, String> {
+	let mut paths = Vec::new();
+	for value in values {
+		paths.extend(parse_path(value, warnings)?);
+	}
+	Ok(paths)
+}
+
+fn parse_path(value: &Value, warnings: &mut Vec<String>) -> Result<Vec<(PathBuf, PathConfig)>, String> {
+	let table = match value {
+		Value::String(path) => return Ok(
+			expand_path(path, warnings)?
+				.into_iter()
+				.map(|path| (path, PathConfig::default()))
+				.collect()
+		),
+		Value::Table(table) => table,
+		_ => return Err(String::from("paths must be strings or tables")),
+	};
+	let Some(Value::String(path)) = table.get("path") else {
+		return Err(String::from("path tables must contain a `path` string"));
+	};
+	let mut path_config = PathConfig::default();
+	for (key, value) in table {
+		let invalid = || format!("invalid type for the `{}` field of path tables", key.yellow().bold());
+		match key.as_str() {
+			"path" => {}
+			"as" => path_config.alias = Some(PathBuf::from(value.as_str().ok_or_else(invalid)?)),
+			"exclude" => path_config.exclude = Some(
+				value
+					.as_array()
+					.ok_or_else(invalid)?
+					.iter()
+					.map(|pattern| map!(
+						pattern,
+						"excluded patterns must be strings",
+						value.as_str() -> parse_excluded_pattern
+					))
+					.collect()
+			),
+			"exclude_tags" => path_config.exclude_tags = Some(
+				value
+					.as_table()
+					.ok_or_else(invalid)?
+					.iter()
+					.map(|tag| map!(tag, value -> parse_excluded_tag))
+					.collect()
+			),
+			"follow_symlinks" => path_config.follow_symlinks = Some(value.as_bool().ok_or_else(invalid)?),
+			_ => return Err(format!("unknown field `{}` in path table", key.yellow().bold())),
+		}
+	}
+	let paths = expand_path(path, warnings)?;
+	if path_config.alias.is_some() && paths.len() > 1 {
+		return Err(format!(
+			"`{}` matches more than one path, so it cannot be named with `{}`",
+			path.yellow().bold(),
+			"as".yellow().bold(),
+		));
+	}
+	Ok(paths.into_iter().map(|path| (path, path_config.clone())).collect())
+}
+
+fn parse_transform(s: &str) -> Result<Transform, &'static str> {
+	const INVALID: &str = "transform rules must look like `s/regex/replacement/flags`";
+	let mut chars = s.chars();
+	let (Some('s'), Some(delimiter)) = (chars.next(), chars.next()) else {
+		return Err(INVALID);
+	};
+	let mut parts = vec![String::new()];
+	let mut escaped = false;
+	for c in chars {
+		let part = parts.last_mut().unwrap();
+		if escaped {
+			if c != delimiter {
+				part.push('\\');
+			}
+			part.push(c);
+			escaped = false;
+		} else if c == '\\' {
+			escaped = true;
+		} else if c == delimiter {
+			parts.push(String::new());
+		} else {
+			part.push(c);
+		}
+	}
+	let [pattern, sed_replacement, flags] = parts.as_slice() else {
+		return Err(INVALID);
+	};
+	let mut replacement = String::with_capacity(sed_replacement.len());
+	let mut chars = sed_replacement.chars();
+	while let Some(c) = chars.next() {
+		match c {
+			'&' => replacement.push_str("${0}"),
+			'$' => replacement.push_str("$$"),
+			'\\' => match chars.next() {
+				Some(group @ '0'..='9') => replacement.push_str(&format!("${{{group}}}")),
+				Some('$') => replacement.push_str("$$"),
+				Some(c) => replacement.push(c),
+				None => replacement.push('\\'),
+			},
+			c => replacement.push(c),
+		}
+	}
+	let mut limit = 1;
+	let mut case_insensitive = false;
+	for flag in flags.chars() {
+		match flag {
+			'g' => limit = 0,
+			'i' => case_insensitive = true,
+			_ => return Err("unknown transform rule flag"),
+		}
+	}
+	Ok(Transform {
+		pattern: bytes::RegexBuilder::new(pattern)
+			.case_insensitive(case_insensitive)
+			.build()
+			.unwrap_or_exit(),
+		replacement: replacement.into_bytes(),
+		limit,
+	})
+}
+
+fn parse_id_mapping((id, new_id): (&String, &Value)) -> Result<(u64, u64), &'static str> {
+	Ok((
+		id.parse().map_err(|_| "keys of owner and group maps must be ids")?,
+		map!(
+			new_id,
+			"values of owner and group maps must be ids",
+			value.as_integer() -> |id| u64::try_from(id).map_err(|_| "ids cannot be negative")
+		),
+	))
+}
+
+fn parse_overlap_mode(mode: &str) -> Result<OverlapMode, &str> {
+	Ok(match mode.to_ascii_lowercase().as_str() {
+		"drop-nested" => OverlapMode::DropNested,
+		"keep-nested" => OverlapMode::KeepNested,
+		_ => return Err("unknown overlapping paths mode"),
+	})
+}
+
+fn parse_tar_format(format: &str) -> Result<TarFormat, &str> {
+	Ok(match format.to_ascii_lowercase().as_str() {
+		"gnu" => TarFormat::Gnu,
+		"pax" | "posix" => TarFormat::Pax,
+		"ustar" => TarFormat::Ustar,
+		_ => return Err("unknown tar format"),
+	})
+}
+
+fn parse_sort_order(order: &str) -> Result<SortOrder, &str> {
+	Ok(match order.to_ascii_lowercase().as_str() {
+		"natural" => SortOrder::Natural,
+		"extension" => SortOrder::Extension,
+		"size" => SortOrder::Size,
+		_ => return Err("unknown sort order"),
+	})
+}
+
+fn parse_integrity_check(check: &str) -> Result<IntegrityCheck, &str> {
+	Ok(match check.to_ascii_lowercase().as_str() {
+		"none" => IntegrityCheck::None,
+		"crc32" => IntegrityCheck::Crc32,
+		"crc64" => IntegrityCheck::Crc64,
+		"sha256" | "sha-256" => IntegrityCheck::Sha256,
+		_ => return Err("unknown integrity check"),
+	})
+}
+
+fn parse_match_finder(match_finder: &str) -> Result<MatchFinder, &str> {
+	Ok(match match_finder.to_ascii_lowercase().as_str() {
+		"auto" => MatchFinder::Auto,
+		"hc3" => MatchFinder::Hc3,
+		"hc4" => MatchFinder::Hc4,
+		"bt2" => MatchFinder::Bt2,
+		"bt3" => MatchFinder::Bt3,
+		"bt4" => MatchFinder::Bt4,
+		_ => return Err("unknown match finder"),
+	})
+}
+
+fn parse_memory_limit(limit: &str) -> Result<MemoryLimit, &'static str> {
+	let limit = limit.trim();
+	if let Some(percentage) = limit.strip_suffix('%') {
+		return match percentage.trim().parse() {
+			Ok(percentage @ 1..=100) => Ok(MemoryLimit::Percentage(percentage)),
+			_ => Err("the memory limit percentage must be between 1 and 100"),
+		};
+	}
+	parse_size(limit).map(MemoryLimit::Bytes).ok_or("unknown memory limit")
+}
+
+fn parse_size(size: &str) -> Option<u64> {
+	let size = size.trim();
+	let (bytes, multiplier) = [("KiB", 1 << 10), ("MiB", 1 << 20), ("GiB", 1 << 30), ("TiB", 1 << 40)]
+		.into_iter()
+		.find_map(|(suffix, multiplier)| Some((size.strip_suffix(suffix)?, multiplier)))
+		.unwrap_or((size, 1));
+	bytes.trim().parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn parse_size_value(size: Value) -> Result<u64, &'static str> {
+	match size {
+		Value::Integer(bytes) => u64::try_from(bytes).map_err(|_| "sizes cannot be negative"),
+		Value::String(size) => parse_size(&size).ok_or("unknown size"),
+		_ => Err("sizes must be integers or strings"),
+	}
+}
+
+fn parse_filter(filter: &str) -> Result<Filter, &str> {
+	Ok(match filter.to_ascii_lowercase().as_str() {
+		"x86" => Filter::X86,
+		"powerpc" => Filter::PowerPc,
+		"ia64" => Filter::Ia64,
+		"arm" => Filter::Arm,
+		"armthumb" | "arm-thumb" | "arm_thumb" => Filter::ArmThumb,
+		"arm64" => Filter::Arm64,
+		"sparc" => Filter::Sparc,
+		"riscv" => Filter::RiscV,
+		"delta" => Filter::Delta(1),
+		"lzma2" => Filter::Lzma2,
+		filter => match filter.strip_prefix("delta:").map(str::parse) {
+			Some(Ok(distance @ 1..=256)) => Filter::Delta(distance),
+			Some(_) => return Err("the distance of the delta filter must be between 1 and 256"),
+			None => return Err("unknown filter"),
+		},
+	})
+}
+
+fn check_filter_chain(chain: Vec<Filter>) -> Result<Vec<Filter>, &'static str> {
+	match chain.iter().position(|filter| *filter == Filter::Lzma2) {
+		Some(i) if i == chain.len() - 1 => {}
+		Some(_) => return Err("lzma2 must be the last filter of a filter chain"),
+		None => return Err("filter chains must end with lzma2"),
+	}
+	if chain.len() > 4 {
+		return Err("filter chains cannot contain more than 4 filters");
+	}
+	Ok(chain)
+}
+
+fn parse_extension_filters(
+	(extension, chain): (&String, &Value)
+) -> Result<(OsString, Vec<Filter>), &'static str> {
+	let chain = map!(
+		chain,
+		"extension filter chains must be arrays",
+		value.as_array() -> |chain: &Array| Ok(chain
+			.iter()
+			.map(|filter| map!(filter, "filters must be strings", value.as_str() -> parse_filter))
+			.collect())
+	);
+	Ok((OsString::from(extension.to_lowercase()), check_filter_chain(chain)?))
+}
+
+//makes a path to backup absolute, so that the same path written differently is archived only once,
+//the last component of a path read with `--files-from` is kept as it was listed, so that the symlinks
+//in the list are archived as symlinks rather than as what they link to
+fn resolve_path(path: PathBuf, listed: bool) -> PathBuf {
+	if let (true, Some(parent), Some(name)) = (listed, path.parent(), path.file_name()) {
+		let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+		if let Ok(parent) = parent.canonicalize() {
+			return parent.join(name);
+		}
+	}
+	path.canonicalize().unwrap_or(path)
+}
+
+//the prefix from the configuration is expanded like the paths to backup
+fn parse_strip_prefix(prefix: &str, warnings: &mut Vec<String>) -> Result<Option<PathBuf>, String> {
+	if prefix.is_empty() {
+		return Ok(None);
+	}
+	match expand_path(prefix, warnings)?.as_slice() {
+		[] => Ok(None),
+		[path] => Ok(Some(path.clone())),
+		_ => Err(format!(
+			"`{}` matches more than one path, so it cannot be used as `{}`",
+			prefix.yellow().bold(),
+			"backup.strip_prefix".yellow().bold(),
+		)),
+	}
+}
+
+//the prefix is compared with the resolved paths to backup, so it must be resolved as well
+fn resolve_strip_prefix(prefix: PathBuf, warnings: &mut Vec<String>) -> Option<PathBuf> {
+	match prefix.canonicalize() {
+		Ok(prefix) => Some(prefix),
+		Err(e) => {
+			warnings.push(format!(
+				"{} could not find `{}`, nothing will be stripped from the paths to backup ({e})",
+				"warning:".yellow().bold(),
+				prefix.to_string_lossy().cyan().bold(),
+			));
+			None
+		}
+	}
+}
+
+fn collapse_overlapping_paths(config: &mut Config, mode: OverlapMode) -> Vec<String> {
+	let mut warnings = Vec::new();
+	let mut paths: Vec<PathBuf> = Vec::with_capacity(config.paths.len());
+	let mut unique_paths = HashSet::with_capacity(config.paths.len());
+	for path in config.paths.drain(..) {
+		if !unique_paths.insert(path.clone()) {
+			warnings.push(format!(
+				"{} `{}` was listed more than once, it will only be archived once",
+				"warning:".yellow().bold(),
+				path.to_string_lossy().cyan().bold(),
+			));
+		} else {
+			paths.push(path);
+		}
+	}
+	if !config.recursion {
+		config.paths = paths;
+		return warnings;
+	}
+	//looking the ancestors up keeps this linear in the number of paths, which can be large with `--files-from`
+	for path in &paths {
+		let Some(parent) = path.ancestors().skip(1).find(|parent| unique_paths.contains(*parent)) else {
+			config.paths.push(path.clone());
+			continue;
+		};
+		warnings.push(format!(
+			"{} `{}` is inside of `{}`, it will only be archived {}",
+			"warning:".yellow().bold(),
+			path.to_string_lossy().cyan().bold(),
+			parent.to_string_lossy().cyan().bold(),
+			match mode {
+				OverlapMode::DropNested => "as part of the latter",
+				OverlapMode::KeepNested => "on its own",
+			}
+		));
+		if mode == OverlapMode::KeepNested {
+			config.nested_paths.insert(path.clone());
+			config.paths.push(path.clone());
+		} else {
+			//the options of a dropped path would otherwise apply once its parent's scan reaches it
+			config.path_configs.remove(path);
+		}
+	}
+	warnings
+}
+
+#[cfg(unix)]
+pub fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+	use std::os::unix::ffi::OsStringExt;
+	PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(windows)]
+pub fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+	PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn read_files_from(list: &Path, null: bool) -> Result<Vec<(PathBuf, PathConfig)>, String> {
+	let data = if list == Path::new("-") {
+		let mut data = Vec::new();
+		crate::STDIN_CONSUMED.store(true, Ordering::Relaxed);
+		io::stdin().read_to_end(&mut data).map(|_| data)
+	} else {
+		fs::read(list)
+	}.map_err(|e| format!("could not read `{}`\n{e}", list.to_string_lossy().cyan().bold()))?;
+	Ok(data
+		.split(|byte| *byte == if null { b'\0' } else { b'\n' })
+		.map(|line| if null { line } else { line.strip_suffix(b"\r").unwrap_or(line) })
+		.filter(|line| !line.is_empty())
+		.map(|line| {
+			let path = bytes_to_path(line.to_vec());
+			let name: PathBuf = path
+				.components()
+				.filter(|component| matches!(component, Component::Normal(_)))
+				.collect();
+			let alias = (!name.as_os_str().is_empty()).then_some(name);
+			(path, PathConfig { alias, ..Default::default() })
+		})
+		.collect())
+}
+
 fn get_from_user<T>(f: impl FnOnce(&User) -> T) -> Option<T> {
 	let users = Users::new_with_refreshed_list();
 	let system = System::new_with_specifics(
@@ -314,6 +1091,69 @@ fn parse_excluded_pattern(s: &str) -> Result<bytes::Regex, &str> {
 	}).unwrap_or_exit())
 }
 
+fn read_excluded_patterns(path: &Path) -> Result<Vec<bytes::Regex>, String> {
+	Ok(fs::read_to_string(path)
+		.map_err(|e| format!("could not read `{}`\n{e}", path.to_string_lossy().cyan().bold()))?
+		.lines()
+		.filter(|line| !(line.trim().is_empty() || line.trim_start().starts_with('#')))
+		.map(|line| map!(line.trim_end(), value -> parse_excluded_pattern))
+		.collect())
+}
+
+fn parse_excluded_fs_type(s: &str) -> Result<Regex, &str> {
+	Ok(Regex::new(&format!("^{}$", regex::escape(s).replace(r"\*", ".*"))).unwrap_or_exit())
+}
+
+#[cfg(target_os = "linux")]
+fn get_excluded_mount_points(fs_types: &[Regex], warnings: &mut Vec<String>) -> HashSet<PathBuf> {
+	//spaces, tabs, newlines and backslashes are written as octal escapes, the other bytes as they are
+	fn unescape(field: &[u8]) -> PathBuf {
+		bytes_to_path(bytes::Regex::new(r"\\([0-7]{3})").unwrap().replace_all(field, |caps: &bytes::Captures| {
+			std::str::from_utf8(&caps[1])
+				.ok()
+				.and_then(|digits| u8::from_str_radix(digits, 8).ok())
+				.map_or_else(|| caps[0].to_vec(), |byte| vec![byte])
+		}).into_owned())
+	}
+
+	const MOUNTINFO: &str = "/proc/self/mountinfo";
+	if fs_types.is_empty() {
+		return HashSet::new();
+	}
+	let mountinfo = match fs::read(MOUNTINFO) {
+		Ok(mountinfo) => mountinfo,
+		Err(e) => {
+			warnings.push(format!(
+				"{} could not read `{}`, mount points will not be skipped by their filesystem type ({e})",
+				"warning:".yellow().bold(),
+				MOUNTINFO.cyan().bold(),
+			));
+			return HashSet::new();
+		}
+	};
+	let mut mount_points = HashSet::new();
+	for line in mountinfo.split(|byte| *byte == b'\n') {
+		//the optional fields end with a `-` field, followed by the filesystem type
+		let fields: Vec<&[u8]> = line.split(|byte| *byte == b' ').collect();
+		let Some(separator) = fields.iter().position(|field| *field == b"-") else {
+			continue;
+		};
+		let (Some(mount_point), Some(fs_type)) = (fields.get(4), fields.get(separator + 1)) else {
+			continue;
+		};
+		let fs_type = String::from_utf8_lossy(fs_type);
+		if fs_types.iter().any(|pattern| pattern.is_match(&fs_type)) {
+			mount_points.insert(unescape(mount_point));
+		}
+	}
+	mount_points
+}
+
+#[cfg(not(target_os = "linux"))]
+fn get_excluded_mount_points(_: &[Regex], _: &mut Vec<String>) -> HashSet<PathBuf> {
+	HashSet::new()
+}
+
 fn format_items(items: Vec<Item>) -> String {
 	let date = Local::now();
 	DelayedFormat::new_with_offset(
@@ -377,6 +1217,21 @@ fn parse_name_capture(caps: &Captures) -> String {
 	}
 }
 
+/// Asks whether to change the configuration file with `f`, updating it if it's outdated too.
+pub fn update_file(msg: String, f: impl FnOnce(&mut Table)) -> io::Result<()> {
+	let config = config!();
+	let Some(config_path) = &config.config_path else {
+		println!("{} the default configuration is being used, so it cannot be updated", "notice:".cyan().bold());
+		return Ok(());
+	};
+	let mut table = toml::from_str(&fs::read_to_string(config_path)?).map_err(io::Error::other)?;
+	default::update(config.auto_update_config, msg, &mut table, |update, table| {
+		update(table);
+		f(table);
+		fs::write(config_path, table.to_string())
+	})
+}
+
 pub fn init() -> Result<(), Box<dyn Error>> {
 	let cli = Cli::parse();
 	match cli.color {
@@ -530,12 +1385,61 @@ Create backup using default configuration? [{}/{}]",
 			}
 		)?;
 	}
-	let mut config = Box::new(Config {
-		paths: parse_config_field!(cli.paths || config.backup.paths -> map!(
+	let excluded_fs_types: Vec<Regex> = parse_config_field!(
+		cli.exclude_fs_types -> map!(
+			Vec<String>,
+			parse_excluded_fs_type
+		)
+		|| config.backup.exclude_fs_types [default: Array::new()] -> map!(
+			Array,
+			"excluded filesystem types must be strings",
+			value.as_str() -> parse_excluded_fs_type
+		)
+	);
+	let exclude_from: Vec<PathBuf> = parse_config_field!(
+		config.backup.exclude_from [default: Array::new()] -> map!(
 			Array,
-			"paths must be strings",
+			"exclude files must be strings",
 			value.as_str() -> |s| Ok(PathBuf::from_str(s).unwrap_or_exit())
-		)),
+		)
+	);
+	let overlap_mode = match cli.overlapping_paths {
+		Some(mode) => mode,
+		None => map!(
+			parse_config_field!(
+				config.backup.overlapping_paths [default: String::from("drop-nested")] -> String
+			),
+			value.as_str() -> parse_overlap_mode
+		),
+	};
+	let reproducible = parse_config_field!(
+		cli.reproducible || config.backup.reproducible [default: false] -> bool
+	);
+	let source_date_epoch = match env::var("SOURCE_DATE_EPOCH") {
+		Ok(value) if reproducible => Some(value.parse::<u64>().map_err(|e| format!(
+			"failed to parse environment variable `{}`\n{e}",
+			"SOURCE_DATE_EPOCH".cyan().bold()
+		))?),
+		_ => None,
+	};
+	let mut warnings = Vec::new();
+	let paths: Vec<(PathBuf, PathConfig)> = match &cli.files_from {
+		Some(list) => read_files_from(list, cli.null)?,
+		None => match cli.paths {
+			Some(paths) => paths.into_iter().map(|path| (path, PathConfig::default())).collect(),
+			None => parse_paths(&parse_config_field!(config.backup.paths -> Array), &mut warnings)?,
+		},
+	}
+		.into_iter()
+		.map(|(path, path_config)| (resolve_path(path, cli.files_from.is_some()), path_config))
+		.collect();
+	let mut config = Box::new(Config {
+		command: cli.command,
+		config_path: (!cli.default_config).then(|| cli.config_path.clone()),
+		auto_update_config: cli.auto_update_config,
+		paths: paths.iter().map(|(path, _)| path.clone()).collect(),
+		recursion: cli.files_from.is_none() || cli.recursion,
+		nested_paths: HashSet::new(),
 		exclude: parse_config_field!(
 			cli.exclude -> map!(
 				Vec<String>,
@@ -557,9 +1461,25 @@ Create backup using default configuration? [{}/{}]",
 				value -> parse_excluded_tag
 			)
 		),
+		excluded_mount_points: get_excluded_mount_points(&excluded_fs_types, &mut warnings),
+		one_file_system: parse_config_field!(
+			cli.one_file_system || config.backup.one_file_system [default: false] -> bool
+		),
+		allowed_file_systems: parse_config_field!(
+			config.backup.allowed_file_systems [default: Array::new()] -> map!(
+				Array,
+				"allowed filesystems must be strings",
+				value.as_str() -> |s| Ok(PathBuf::from_str(s).unwrap_or_exit())
+			)
+		),
+		allowed_storage_ids: OnceLock::new(),
 		follow_symlinks: parse_config_field!(
 			cli.follow_symlinks || config.backup.follow_symlinks [default: false] -> bool
 		),
+		preserve_xattrs: parse_config_field!(
+			cli.preserve_xattrs || config.backup.preserve_xattrs [default: false] -> bool
+		),
+		sparse: parse_config_field!(cli.sparse || config.backup.sparse [default: true] -> bool),
 		ignore_unreadable_files: Mutex::new(parse_config_field!(
 			cli.ignore_unreadable_files
 			|| config.backup.ignore_unreadable_files [default: false] -> bool
@@ -572,6 +1492,87 @@ Create backup using default configuration? [{}/{}]",
 			cli.use_multiple_subarchives
 			|| config.backup.use_multiple_subarchives [default: false] -> bool
 		),
+		subarchive_jobs: parse_config_field!(
+			cli.subarchive_jobs
+			|| config.backup.subarchive_jobs [default: 1] -> u32
+		),
+		subarchive_depth: parse_config_field!(
+			cli.subarchive_depth
+			|| config.backup.subarchive_depth [default: 0] -> u32
+		),
+		subarchive_min_size: match cli.subarchive_min_size {
+			Some(size) => map!(size, value.as_str() -> |size| parse_size(size).ok_or("unknown size")),
+			None => map!(
+				parse_config_field!(config.backup.subarchive_min_size [default: Value::from(0)] -> Value),
+				value -> parse_size_value
+			),
+		},
+		subarchive_max_size: match cli.subarchive_max_size {
+			Some(size) => map!(size, value.as_str() -> |size| parse_size(size).ok_or("unknown size")),
+			None => map!(
+				parse_config_field!(config.backup.subarchive_max_size [default: Value::from(0)] -> Value),
+				value -> parse_size_value
+			),
+		},
+		tar_format: match cli.tar_format {
+			Some(format) => format,
+			None => map!(
+				parse_config_field!(config.backup.tar_format [default: String::from("gnu")] -> String),
+				value.as_str() -> parse_tar_format
+			),
+		},
+		sort_order: match cli.sort_order {
+			Some(order) => order,
+			None => map!(
+				parse_config_field!(config.backup.sort_order [default: String::from("natural")] -> String),
+				value.as_str() -> parse_sort_order
+			),
+		},
+		store_extensions: parse_config_field!(
+			config.backup.store_extensions [default: Array::new()] -> map!(
+				Array,
+				"stored extensions must be strings",
+				value.as_str() -> |extension: &str| Ok(OsString::from(extension.to_lowercase()))
+			)
+		),
+		detect_compressed: parse_config_field!(
+			cli.detect_compressed || config.backup.detect_compressed [default: false] -> bool
+		),
+		path_configs: paths.into_iter().collect(),
+		full_paths: parse_config_field!(
+			cli.full_paths || config.backup.full_paths [default: false] -> bool
+		),
+		strip_prefix: match cli.strip_prefix {
+			Some(prefix) => Some(prefix).filter(|prefix| !prefix.as_os_str().is_empty()),
+			None => parse_strip_prefix(
+				&parse_config_field!(config.backup.strip_prefix [default: String::new()] -> String),
+				&mut warnings
+			)?,
+		}
+			.and_then(|prefix| resolve_strip_prefix(prefix, &mut warnings)),
+		transform: parse_config_field!(
+			config.backup.transform [default: Array::new()] -> map!(
+				Array,
+				"transform rules must be strings",
+				value.as_str() -> parse_transform
+			)
+		),
+		owner_map: parse_config_field!(
+			config.backup.owner_map [default: Table::new()] -> map!(Table, value -> parse_id_mapping)
+		),
+		group_map: parse_config_field!(
+			config.backup.group_map [default: Table::new()] -> map!(Table, value -> parse_id_mapping)
+		),
+		numeric_owner: parse_config_field!(
+			cli.numeric_owner || config.backup.numeric_owner [default: true] -> bool
+		),
+		reproducible,
+		clamp_mtime: match cli.clamp_mtime {
+			Some(clamp_mtime) => Some(clamp_mtime),
+			None => parse_config_field!(
+				config.backup.clamp_mtime [default: source_date_epoch] -> Option<u64>
+			),
+		},
 		name: Regex::new(r"%(![a-z]+)?([^% ]*)?")?.replace_all(
 			&parse_config_field!(cli.name || config.backup.name -> String),
 			parse_name_capture
@@ -598,30 +1599,120 @@ Create backup using default configuration? [{}/{}]",
 		level: parse_config_field!(cli.level || config.xz.level -> u32),
 		threads: parse_config_field!(cli.threads || config.xz.threads -> u32),
 		block_size: parse_config_field!(cli.block_size || config.xz.block_size [default: 0] -> u64),
+		extreme: parse_config_field!(cli.extreme || config.xz.extreme [default: false] -> bool),
+		dict_size: parse_config_field!(cli.dict_size || config.xz.dict_size [default: 0] -> u32),
+		lc: parse_config_field!(cli.lc || config.xz.lc [default: 3] -> u32),
+		lp: parse_config_field!(cli.lp || config.xz.lp [default: 0] -> u32),
+		pb: parse_config_field!(cli.pb || config.xz.pb [default: 2] -> u32),
+		match_finder: match cli.match_finder {
+			Some(match_finder) => match_finder,
+			None => map!(
+				parse_config_field!(config.xz.match_finder [default: String::from("auto")] -> String),
+				value.as_str() -> parse_match_finder
+			),
+		},
+		nice_len: parse_config_field!(cli.nice_len || config.xz.nice_len [default: 0] -> u32),
+		filters: map!(
+			if cli.filters.is_empty() {
+				parse_config_field!(
+					config.xz.filters [default: vec![Value::from("lzma2")]] -> map!(
+						Array,
+						"filters must be strings",
+						value.as_str() -> parse_filter
+					)
+				)
+			} else {
+				cli.filters.iter().map(|value| map!(value, value.as_str() -> parse_filter)).collect()
+			},
+			value -> check_filter_chain
+		),
+		extension_filters: parse_config_field!(
+			config.xz.extension_filters [default: Table::new()] -> map!(Table, value -> parse_extension_filters)
+		),
+		check: match cli.check {
+			Some(check) => check,
+			None => map!(
+				parse_config_field!(config.xz.check [default: String::from("crc64")] -> String),
+				value.as_str() -> parse_integrity_check
+			),
+		},
+		memory_limit: match cli.memory_limit {
+			Some(limit) => map!(limit, value.as_str() -> parse_memory_limit),
+			None => map!(
+				parse_config_field!(config.xz.memory_limit [default: Value::from(0)] -> Value),
+				value -> |limit| match limit {
+					Value::Integer(bytes) => u64::try_from(bytes)
+						.map(MemoryLimit::Bytes)
+						.map_err(|_| "the memory limit cannot be negative"),
+					Value::String(limit) => parse_memory_limit(&limit),
+					_ => Err("the memory limit must be an integer or a string"),
+				}
+			),
+		},
 	});
-	config.paths.extend(cli.add_paths);
-	config.exclude.extend(
-		cli.add_exclude
-			.into_iter()
-			.map(|value| map!(value, value.as_str() -> parse_excluded_pattern))
-	);
-	config.exclude_tags.extend(
-		cli.exclude_tags
+	config.paths.extend(cli.add_paths.into_iter().map(|path| resolve_path(path, false)));
+	warnings.extend(collapse_overlapping_paths(&mut config, overlap_mode));
+	if !config.extension_filters.is_empty() && !config.use_multiple_subarchives {
+		warnings.push(format!(
+			"{} `{}` is only used together with `{}`",
+			"warning:".yellow().bold(),
+			"xz.extension_filters".cyan().bold(),
+			"backup.use_multiple_subarchives".cyan().bold(),
+		));
+	}
+	if config.sparse && config.tar_format != TarFormat::Gnu {
+		warnings.push(format!(
+			"{} `{}` is only used together with the `{}` `{}`, sparse files will be stored in full",
+			"warning:".yellow().bold(),
+			"backup.sparse".cyan().bold(),
+			"gnu".cyan().bold(),
+			"backup.tar_format".cyan().bold(),
+		));
+		config.sparse = false;
+	}
+	//`enter_root` looks the paths up by their options, so every path to backup needs some
+	for path in &config.paths {
+		config.path_configs.entry(path.clone()).or_default();
+	}
+	config.allowed_file_systems.extend(cli.allow_file_systems);
+	config.transform.extend(
+		cli.transform
 			.into_iter()
-			.map(|tag| (tag, TagKeepMode::Tag))
-			.chain(
-				cli.exclude_tags_under
-					.into_iter()
-					.map(|tag| (tag, TagKeepMode::Dir))
-			)
-			.chain(
-				cli.exclude_tags_all
-					.into_iter()
-					.map(|tag| (tag, TagKeepMode::None))
-			)
+			.map(|value| map!(value, value.as_str() -> parse_transform))
 	);
-	// SAFETY: There is only one thread running for now
-	unsafe { CONFIG.set(Box::leak(config)) }
+	let mut added_exclude: Vec<bytes::Regex> = cli.add_exclude
+		.into_iter()
+		.map(|value| map!(value, value.as_str() -> parse_excluded_pattern))
+		.collect();
+	for path in exclude_from.iter().chain(&cli.exclude_from) {
+		added_exclude.extend(read_excluded_patterns(path)?);
+	}
+	let added_exclude_tags: Vec<(OsString, TagKeepMode)> = cli.exclude_tags
+		.into_iter()
+		.map(|tag| (tag, TagKeepMode::Tag))
+		.chain(
+			cli.exclude_tags_under
+				.into_iter()
+				.map(|tag| (tag, TagKeepMode::Dir))
+		)
+		.chain(
+			cli.exclude_tags_all
+				.into_iter()
+				.map(|tag| (tag, TagKeepMode::None))
+		)
+		.collect();
+	//the options of a path replace the ones of the configuration, but not what's added to them
+	for path_config in config.path_configs.values_mut() {
+		if let Some(exclude) = &mut path_config.exclude {
+			exclude.extend(added_exclude.iter().cloned());
+		}
+		if let Some(exclude_tags) = &mut path_config.exclude_tags {
+			exclude_tags.extend(added_exclude_tags.iter().cloned());
+		}
+	}
+	config.exclude.extend(added_exclude);
+	config.exclude_tags.extend(added_exclude_tags);
+	*CONFIG.write().unwrap() = Some(Box::leak(config));
 	println!(
 		"{}{} configuration! (`{config_path_str}`)",
 		if *config!(progress_bars) {
@@ -631,5 +1722,8 @@ Create backup using default configuration? [{}/{}]",
 		},
 		"Loaded".green().bold()
 	);
+	for warning in warnings {
+		eprintln!("{warning}");
+	}
 	Ok(())
 }
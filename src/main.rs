@@ -1,7 +1,7 @@
+use config::{config, Command};
 use error::ResultExt;
-use std::panic;
+use std::{fs::File, io::{self, BufRead, BufReader}, panic, sync::atomic::{AtomicBool, Ordering}};
 
-mod static_ptr;
 mod error;
 mod config;
 mod backup;
@@ -10,7 +10,7 @@ macro_rules! input {
 	($question:expr => { $($char:literal => $code:expr,)+ _ => $default:expr, }) => {{
 		eprintln!("{}", $question);
 		let mut choice = String::new();
-		io::stdin().read_line(&mut choice).unwrap_or_exit();
+		crate::read_answer(&mut choice).unwrap_or_exit();
 		match choice.trim_start().as_bytes().first() {
 			Some(byte) => match byte.to_ascii_lowercase() {
 				$($char => $code,)+
@@ -23,6 +23,28 @@ macro_rules! input {
 
 pub(crate) use input;
 
+/// Set once stdin has been read to the end by `--files-from -`.
+pub static STDIN_CONSUMED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+const TERMINAL: &str = "/dev/tty";
+
+#[cfg(windows)]
+const TERMINAL: &str = "CONIN$";
+
+//answers are read from the terminal once stdin was used for the list of paths, from stdin otherwise
+pub fn read_answer(answer: &mut String) -> io::Result<usize> {
+	if !STDIN_CONSUMED.load(Ordering::Relaxed) {
+		return io::stdin().read_line(answer);
+	}
+	match File::open(TERMINAL) {
+		Ok(terminal) => BufReader::new(terminal).read_line(answer),
+		Err(_) => Err(io::Error::other(
+			"could not ask for an answer, stdin was used by `--files-from` and there is no terminal",
+		)),
+	}
+}
+
 #[cfg(feature = "pause")]
 pub fn pause() {
 	let mut buf = String::new();
@@ -34,7 +56,11 @@ fn main() {
 	colored::control::set_virtual_terminal(true).unwrap();
 	panic::set_hook(Box::new(error::panic_hook));
 	config::init().unwrap_or_exit();
-	backup::init().unwrap_or_exit();
+	match &config!().command {
+		Some(Command::Estimate { sample, levels }) => backup::estimate::run(*sample, levels),
+		Some(Command::Bench { size }) => backup::bench::run(*size),
+		None => backup::init(),
+	}.unwrap_or_exit();
 	#[cfg(feature = "pause")]
 	pause();
 }
@@ -0,0 +1,35 @@
+use std::{env, fs::{self, File}, path::Path, process::{self, Command}};
+
+fn write(path: &Path, contents: &str) {
+	fs::create_dir_all(path.parent().unwrap()).unwrap();
+	fs::write(path, contents).unwrap();
+}
+
+//a path to backup nested inside of another one must only get the subarchive of its own
+#[test]
+fn nested_paths_are_archived_once() {
+	let dir = env::temp_dir().join(format!("baxzup-nested-{}", process::id()));
+	let _ = fs::remove_dir_all(&dir);
+	write(&dir.join("src/a/x"), "x");
+	write(&dir.join("src/a/b/y"), "y");
+	write(&dir.join("src/c/z"), "z");
+	let status = Command::new(env!("CARGO_BIN_EXE_baxzup"))
+		.current_dir(&dir)
+		.arg("--default-config")
+		.args(["--quiet", "--progress-bars", "false", "--force-overwrite", "--level", "0"])
+		.arg("--paths")
+		.arg(format!("{},{}", dir.join("src").display(), dir.join("src/a/b").display()))
+		.args(["--overlapping-paths", "keep-nested", "--use-multiple-subarchives", "--subarchive-depth", "3"])
+		.args(["--name", "backup.tar"])
+		.status()
+		.unwrap();
+	assert!(status.success());
+	let mut names: Vec<_> = tar::Archive::new(File::open(dir.join("backup.tar")).unwrap())
+		.entries()
+		.unwrap()
+		.map(|entry| entry.unwrap().path().unwrap().to_string_lossy().into_owned())
+		.collect();
+	names.sort();
+	assert_eq!(names, ["b.tar.xz", "src/a.tar.xz", "src/c.tar.xz"]);
+	fs::remove_dir_all(&dir).unwrap();
+}